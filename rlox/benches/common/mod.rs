@@ -0,0 +1,115 @@
+//! The programs and timing shared by the benchmarks.
+//!
+//! Fib, loops, string concatenation and method calls come first. Programs the
+//! compiler cannot compile yet are reported as unsupported and skipped. The
+//! straight-line blocks after them are run many times and execute every op
+//! exactly once.
+
+use std::time::{Duration, Instant};
+
+use rlox::lc::ParseError;
+use rlox::vm::VM;
+
+pub const RUNS: usize = 2_000;
+pub const SAMPLES: usize = 5;
+
+const FIB: &str = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 2) + fib(n - 1);
+}
+print fib(15);
+";
+
+const LOOPS: &str = "
+var sum = 0;
+var i = 0;
+while (i < 1000) {
+  sum = sum + i;
+  i = i + 1;
+}
+print sum;
+";
+
+const STRING_CONCAT: &str = "
+var s = \"\";
+for (var i = 0; i < 200; i = i + 1) {
+  s = s + \"x\";
+}
+print s;
+";
+
+const METHOD_CALLS: &str = "
+class Counter {
+  init() { this.count = 0; }
+  increment() { this.count = this.count + 1; }
+}
+var counter = Counter();
+for (var i = 0; i < 1000; i = i + 1) {
+  counter.increment();
+}
+print counter.count;
+";
+
+fn repeat(statement: &str) -> String {
+    statement.repeat(200)
+}
+
+pub fn programs() -> Vec<(&'static str, String)> {
+    vec![
+        ("fib", FIB.to_string()),
+        ("loops", LOOPS.to_string()),
+        ("string concat", STRING_CONCAT.to_string()),
+        ("method calls", METHOD_CALLS.to_string()),
+        ("increment", format!("{{ var i = 0; {} print i; }}", repeat("i = i + 1;"))),
+        ("add locals", format!("{{ var a = 1; var b = 2; var c; {} print c; }}", repeat("c = a + b;"))),
+        ("add constant", format!("{{ var a = 1; var b; {} print b; }}", repeat("b = a + 2;"))),
+        (
+            "arithmetic",
+            format!("{{ var a = 1; var b = 2; var c = 3; {} print c; }}", repeat("c = a * b - c / b + a;")),
+        ),
+        ("comparisons", format!("{{ var a = 1; var b = 2; var c; {} print c; }}", repeat("c = a < b == !c;"))),
+        ("globals", format!("var a = 1; var b = 2; {} print a;", repeat("a = a + b;"))),
+        ("strings", format!("{{ var s = \"\"; {} print s; }}", repeat("s = s + \"x\";"))),
+        ("native calls", format!("{{ var n; {} print n; }}", repeat("n = argc();"))),
+    ]
+}
+
+/// Whether `name` did not compile because it uses a part of Lox the compiler
+/// does not implement yet, which is reported. Panics on other errors.
+pub fn unsupported(name: &str, errors: &[ParseError]) -> bool {
+    match errors.first() {
+        None => false,
+        Some(error) if error.is_unsupported() => {
+            println!("{:<14} unsupported: {}", name, error);
+            true
+        }
+        Some(error) => panic!("{}: {}", name, error),
+    }
+}
+
+/// Fastest of `SAMPLES` timings of `RUNS` calls to `run`, each with a new
+/// VM, and the instructions a single run executes. Creating the VMs is not
+/// timed.
+pub fn time(mut run: impl FnMut(&mut VM)) -> (Duration, u64) {
+    let mut instructions = 0;
+    let time = (0..SAMPLES)
+        .map(|_| {
+            let mut vms: Vec<VM> = (0..RUNS).map(|_| VM::new()).collect();
+            let start = Instant::now();
+            for vm in &mut vms {
+                run(vm);
+            }
+            let time = start.elapsed();
+            instructions = vms[0].instructions_executed();
+            time
+        })
+        .min()
+        .unwrap_or_default();
+    (time, instructions)
+}
+
+/// Instructions executed and time of a single run.
+pub fn per_run((time, instructions): (Duration, u64)) -> String {
+    format!("{:>6} ops {:>7.1}µs", instructions, time.as_secs_f64() * 1e6 / RUNS as f64)
+}
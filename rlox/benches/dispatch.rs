@@ -0,0 +1,32 @@
+//! Times the `VM::run` dispatch loop on short instructions, where its
+//! per-instruction overhead dominates.
+//!
+//!     cargo bench --bench dispatch
+
+mod common;
+
+use std::hint::black_box;
+use std::io;
+
+use common::{per_run, programs, time, unsupported, RUNS};
+use rlox::bc::Chunk;
+use rlox::lc;
+
+fn main() {
+    println!("{:<14} {:>20} {:>8}", "program", "per run", "per op");
+
+    for (name, source) in programs() {
+        let mut chunk = Chunk::new();
+        if unsupported(name, &lc::compile(&source, &mut chunk)) {
+            continue;
+        }
+
+        let (time, instructions) = time(|vm| vm.run(black_box(&chunk), &mut io::sink()).unwrap());
+        println!(
+            "{:<14} {:>20} {:>6.1}ns",
+            name,
+            per_run((time, instructions)),
+            time.as_secs_f64() * 1e9 / (RUNS as u64 * instructions) as f64
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Times the register VM against the stack VM, see `reg`.
+//!
+//!     cargo bench --bench registers
+//!
+//! The stack VM runs fully optimized code.
+
+mod common;
+
+use std::hint::black_box;
+use std::io;
+
+use common::{per_run, programs, time, unsupported};
+use rlox::bc::Chunk;
+use rlox::{lc, reg, regcodegen};
+
+fn main() {
+    println!("{:<14} {:>20} {:>20} {:>8}", "program", "stack", "registers", "speedup");
+
+    for (name, source) in programs() {
+        let mut stack = Chunk::new();
+        if unsupported(name, &lc::compile(&source, &mut stack)) {
+            continue;
+        }
+        let mut registers = reg::Chunk::new();
+        if unsupported(name, &regcodegen::compile(&source, &mut registers)) {
+            continue;
+        }
+
+        let stack_time = time(|vm| vm.run(black_box(&stack), &mut io::sink()).unwrap());
+        let registers_time = time(|vm| vm.run_registers(black_box(&registers), &mut io::sink()).unwrap());
+
+        println!(
+            "{:<14} {:>20} {:>20} {:>7.2}x",
+            name,
+            per_run(stack_time),
+            per_run(registers_time),
+            stack_time.0.as_secs_f64() / registers_time.0.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Times `VM::run` on the benchmark programs with and without
+//! superinstructions, see `opt::peephole`.
+//!
+//!     cargo bench --bench superinstructions
+
+mod common;
+
+use std::hint::black_box;
+use std::io;
+
+use common::{per_run, programs, time, unsupported};
+use rlox::bc::Chunk;
+use rlox::lc;
+use rlox::opt::Options;
+
+fn main() {
+    println!("{:<14} {:>20} {:>20} {:>8}", "program", "plain", "fused", "speedup");
+
+    for (name, source) in programs() {
+        let mut plain = Chunk::new();
+        if unsupported(name, &lc::compile_with(&source, &mut plain, Options { superinstructions: false })) {
+            continue;
+        }
+        let mut fused = Chunk::new();
+        if unsupported(name, &lc::compile(&source, &mut fused)) {
+            continue;
+        }
+
+        let plain_time = time(|vm| vm.run(black_box(&plain), &mut io::sink()).unwrap());
+        let fused_time = time(|vm| vm.run(black_box(&fused), &mut io::sink()).unwrap());
+
+        println!(
+            "{:<14} {:>20} {:>20} {:>7.2}x",
+            name,
+            per_run(plain_time),
+            per_run(fused_time),
+            plain_time.0.as_secs_f64() / fused_time.0.as_secs_f64()
+        );
+    }
+}
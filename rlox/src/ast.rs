@@ -0,0 +1,338 @@
+//! Syntax tree of a Lox program and the parser that builds it.
+//!
+//! Together with `resolver` and `codegen` this is a multi-pass alternative to
+//! the single-pass `lc::compile`: `parse` builds the tree, `resolver::resolve`
+//! binds every variable to a local slot or a global, and `codegen::generate`
+//! emits the chunk. Both compilers produce the same bytecode.
+
+use std::borrow::Cow;
+
+use crate::lc::{self, Cursor, ParseError, ParseErrorKind, Precedence, Scanner, Token, TokenParser, TokenType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal<'src> {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    /// With escape sequences decoded.
+    String(Cow<'src, str>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+/// Where a variable lives at runtime. The parser binds every variable to a
+/// global; `resolver::resolve` rebinds the ones declared in a block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Binding {
+    #[default]
+    Global,
+    /// Slot on the value stack.
+    Local(u8),
+}
+
+/// An expression. Each node keeps the token the compiled code is attributed to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr<'src> {
+    Literal {
+        value: Literal<'src>,
+        token: Token<'src>,
+    },
+    Grouping(Box<Expr<'src>>),
+    Unary {
+        op: UnaryOp,
+        token: Token<'src>,
+        operand: Box<Expr<'src>>,
+    },
+    Binary {
+        op: BinaryOp,
+        token: Token<'src>,
+        left: Box<Expr<'src>>,
+        right: Box<Expr<'src>>,
+    },
+    Variable {
+        name: Token<'src>,
+        binding: Binding,
+    },
+    Assign {
+        name: Token<'src>,
+        value: Box<Expr<'src>>,
+        binding: Binding,
+    },
+    Call {
+        callee: Box<Expr<'src>>,
+        paren: Token<'src>,
+        arguments: Vec<Expr<'src>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt<'src> {
+    Expression {
+        expression: Expr<'src>,
+        semicolon: Token<'src>,
+    },
+    Print {
+        keyword: Token<'src>,
+        expression: Expr<'src>,
+    },
+    Var {
+        keyword: Token<'src>,
+        name: Token<'src>,
+        initializer: Option<Expr<'src>>,
+        binding: Binding,
+    },
+    Block {
+        statements: Vec<Stmt<'src>>,
+        right_brace: Token<'src>,
+    },
+}
+
+type Result<'src, T> = std::result::Result<T, ParseError<'src>>;
+
+/// Parses the whole source. Reports the same syntax errors as `lc::compile`,
+/// but none of the errors found by `resolver::resolve` or `codegen::generate`.
+pub fn parse(source: &str) -> std::result::Result<Vec<Stmt<'_>>, Vec<ParseError<'_>>> {
+    let mut parser = Parser {
+        cursor: Cursor::new(Scanner::new(source)),
+    };
+
+    let mut statements = Vec::new();
+    while parser.cursor.tokens.peek().is_some() {
+        statements.extend(parser.declaration());
+    }
+    parser.cursor.report_scan_errors();
+
+    if parser.cursor.errors.is_empty() {
+        Ok(statements)
+    } else {
+        Err(parser.cursor.errors)
+    }
+}
+
+struct Parser<'src> {
+    cursor: Cursor<'src>,
+}
+
+impl<'src> TokenParser<'src> for Parser<'src> {
+    fn cursor(&mut self) -> &mut Cursor<'src> {
+        &mut self.cursor
+    }
+}
+
+impl<'src> Parser<'src> {
+    fn expression(&mut self, min_prec: Precedence) -> Result<'src, Expr<'src>> {
+        self.nested(|parser| parser.parse_precedence(min_prec))
+    }
+
+    fn parse_precedence(&mut self, min_prec: Precedence) -> Result<'src, Expr<'src>> {
+        let Some(token) = self.cursor.tokens.next() else {
+            return Err(self.cursor.error_end(ParseErrorKind::IncompleteExpression));
+        };
+
+        let literal = |value| Expr::Literal { value, token: token.clone() };
+        let mut expr = match token.ttype {
+            TokenType::Minus | TokenType::Bang => {
+                let operand = self.expression(Precedence::Unary)?;
+                let op = if token.ttype == TokenType::Minus { UnaryOp::Negate } else { UnaryOp::Not };
+                Expr::Unary { op, token, operand: Box::new(operand) }
+            }
+            TokenType::Number => {
+                let value = lc::number_value(token.span)
+                    .map_err(|_| ParseError::at(token.clone(), ParseErrorKind::InvalidNumber))?;
+                literal(Literal::Number(value))
+            }
+            TokenType::String => {
+                let without_quotes = &token.span[1..(token.span.len() - 1)];
+                let string = lc::unescape(without_quotes)
+                    .map_err(|(range, kind)| ParseError::at(token.slice(range.start + 1..range.end + 1), kind))?;
+                literal(Literal::String(string))
+            }
+            TokenType::LeftParen => {
+                let inner = self.expression(Precedence::None)?;
+                self.cursor.must_consume(TokenType::RightParen, ParseErrorKind::NoRightParenAfterExpression)?;
+                Expr::Grouping(Box::new(inner))
+            }
+            TokenType::Nil => literal(Literal::Nil),
+            TokenType::True => literal(Literal::Bool(true)),
+            TokenType::False => literal(Literal::Bool(false)),
+            TokenType::Identifier => {
+                if let Some(eq_token) = self.cursor.tokens.next_if(|token| token.ttype == TokenType::Equal) {
+                    if min_prec > Precedence::Assignment {
+                        return Err(ParseError::at(eq_token, ParseErrorKind::InvalidAssignmentTarget));
+                    }
+
+                    let value = self.expression(Precedence::Assignment)?;
+                    Expr::Assign { name: token, value: Box::new(value), binding: Binding::Global }
+                } else {
+                    Expr::Variable { name: token, binding: Binding::Global }
+                }
+            }
+            _ => return Err(ParseError::at(token, ParseErrorKind::IncompleteExpression)),
+        };
+
+        while let Some((token, op_prec)) = self.cursor.next_operator(min_prec) {
+            if token.ttype == TokenType::LeftParen {
+                let mut arguments = Vec::new();
+                self.arguments(|parser| {
+                    arguments.push(parser.expression(Precedence::None)?);
+                    Ok(())
+                })?;
+                expr = Expr::Call { callee: Box::new(expr), paren: token, arguments };
+                continue;
+            }
+
+            let right = self.expression(op_prec)?;
+
+            let op = match token.ttype {
+                TokenType::Plus => BinaryOp::Add,
+                TokenType::Minus => BinaryOp::Subtract,
+                TokenType::Star => BinaryOp::Multiply,
+                TokenType::Slash => BinaryOp::Divide,
+                TokenType::EqualEqual => BinaryOp::Equal,
+                TokenType::BangEqual => BinaryOp::NotEqual,
+                TokenType::Greater => BinaryOp::Greater,
+                TokenType::GreaterEqual => BinaryOp::GreaterEqual,
+                TokenType::Less => BinaryOp::Less,
+                TokenType::LessEqual => BinaryOp::LessEqual,
+                _ => return Err(ParseError::at(token, ParseErrorKind::InvalidAssignmentTarget)),
+            };
+
+            expr = Expr::Binary { op, token, left: Box::new(expr), right: Box::new(right) };
+        }
+
+        Ok(expr)
+    }
+
+    fn block(&mut self) -> Result<'src, Stmt<'src>> {
+        let mut statements = Vec::new();
+        loop {
+            if let Some(right_brace) = self.cursor.tokens.next_if(|token| token.ttype == TokenType::RightBrace) {
+                break Ok(Stmt::Block { statements, right_brace });
+            }
+
+            if self.cursor.tokens.peek().is_none() {
+                break Err(self.cursor.error_end(ParseErrorKind::RightBraceAfterBlock));
+            }
+
+            statements.extend(self.declaration());
+        }
+    }
+
+    fn statement(&mut self) -> Result<'src, Stmt<'src>> {
+        if let Some(keyword) = self.cursor.tokens.next_if(|token| token.ttype == TokenType::Print) {
+            let expression = self.expression(Precedence::None)?;
+            self.cursor.must_consume(TokenType::Semicolon, ParseErrorKind::NoSemicolonAfterValue)?;
+            Ok(Stmt::Print { keyword, expression })
+        } else if self.cursor.tokens.next_if(|token| token.ttype == TokenType::LeftBrace).is_some() {
+            self.nested(|parser| parser.block())
+        } else {
+            let expression = self.expression(Precedence::None)?;
+            let semicolon = self.cursor.must_consume(TokenType::Semicolon, ParseErrorKind::NoSemicolonAfterExpression)?;
+            Ok(Stmt::Expression { expression, semicolon })
+        }
+    }
+
+    fn var_declaration(&mut self, keyword: Token<'src>) -> Result<'src, Stmt<'src>> {
+        let name = self.cursor.variable_name()?;
+
+        let initializer = match self.cursor.tokens.next_if(|token| token.ttype == TokenType::Equal) {
+            Some(_) => Some(self.expression(Precedence::None)?),
+            None => None,
+        };
+
+        self.cursor.must_consume(TokenType::Semicolon, ParseErrorKind::NoSemicolonAfterVarDecl)?;
+
+        Ok(Stmt::Var { keyword, name, initializer, binding: Binding::Global })
+    }
+
+    /// The next declaration, or `None` after reporting an error in it.
+    fn declaration(&mut self) -> Option<Stmt<'src>> {
+        let result = match self.cursor.tokens.next_if(|token| token.ttype == TokenType::Var) {
+            Some(keyword) => self.var_declaration(keyword),
+            None => self.statement(),
+        };
+
+        self.cursor.end_declaration(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(source: &str) -> Vec<Stmt<'_>> {
+        parse(source).unwrap_or_else(|errors| panic!("{:?}", errors))
+    }
+
+    #[test]
+    fn operator_precedence() {
+        let statements = parse_ok("print -1 + 2 * f(3) >= 4;");
+        let [Stmt::Print { expression, .. }] = &statements[..] else {
+            panic!("{:?}", statements);
+        };
+
+        let Expr::Binary { op: BinaryOp::GreaterEqual, left, .. } = expression else {
+            panic!("{:?}", expression);
+        };
+        let Expr::Binary { op: BinaryOp::Add, left: negated, right: product, .. } = &**left else {
+            panic!("{:?}", left);
+        };
+        assert!(matches!(**negated, Expr::Unary { op: UnaryOp::Negate, .. }));
+        let Expr::Binary { op: BinaryOp::Multiply, right: call, .. } = &**product else {
+            panic!("{:?}", product);
+        };
+        assert!(matches!(&**call, Expr::Call { arguments, .. } if arguments.len() == 1));
+    }
+
+    #[test]
+    fn assignment_is_right_associative() {
+        let statements = parse_ok("a = b = \"\\t\";");
+        let [Stmt::Expression { expression: Expr::Assign { name, value, .. }, .. }] = &statements[..] else {
+            panic!("{:?}", statements);
+        };
+
+        assert_eq!(name.span, "a");
+        let Expr::Assign { name, value, .. } = &**value else {
+            panic!("{:?}", value);
+        };
+        assert_eq!(name.span, "b");
+        assert!(matches!(&**value, Expr::Literal { value: Literal::String(string), .. } if string == "\t"));
+    }
+
+    #[test]
+    fn syntax_errors_match_single_pass() {
+        let sources = [
+            "print 1",
+            "var nil = 1;",
+            "(a) = 1; a = 1 = 2; 1 + a = 2;",
+            "{ var a = 1; print a",
+            "print \"\\q\"; print 0x;",
+            "print f(1, 2;",
+            "var x = \"open",
+        ];
+
+        for source in sources {
+            let single_pass = lc::compile(source, &mut crate::bc::Chunk::new());
+            assert!(!single_pass.is_empty(), "{}", source);
+            assert_eq!(parse(source).unwrap_err(), single_pass, "{}", source);
+        }
+    }
+}
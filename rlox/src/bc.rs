@@ -1,4 +1,7 @@
-use crate::gc::{GcHandle, Object};
+use crate::gc::{GcHandle, ObjectType};
+use crate::natives::NATIVES;
+#[cfg(not(feature = "nan-boxing"))]
+use crate::gc::Object;
 use std::collections::LinkedList;
 use std::convert::From;
 use std::fmt::Debug;
@@ -20,19 +23,37 @@ pub enum Op {
     Equal,
     Greater,
     Less,
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
 
     Print,
     Pop,
 
-    DefineGlobal { offset: u8 },
-    GetGlobal { offset: u8 },
-    SetGlobal { offset: u8 },
+    /// The global variable instructions take a slot, see `Globals`.
+    DefineGlobal { slot: u8 },
+    GetGlobal { slot: u8 },
+    SetGlobal { slot: u8 },
 
     GetLocal { offset: u8 },
     SetLocal { offset: u8 },
+
+    Call { arg_count: u8 },
+
+    // Superinstructions, see `opt::peephole`.
+    /// `GetLocal { offset: first }, GetLocal { offset: second }`.
+    GetLocal2 { first: u8, second: u8 },
+    /// `GetLocal { offset: slot }, Constant { offset }, Add`.
+    AddLocalConst { slot: u8, offset: u8 },
+    /// `AddLocalConst { slot, offset }, SetLocal { offset: slot }, Pop`.
+    IncrementLocal { slot: u8, offset: u8 },
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// A Lox value. With the `nan-boxing` feature, values are packed into a
+/// single `u64` instead, see `nanbox::Value`. Code outside this module only
+/// uses the API shared by both representations.
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Value {
     Nil,
     Bool(bool),
@@ -40,7 +61,63 @@ pub enum Value {
     Obj(Object),
 }
 
+/// The dynamic type of a `Value`, as reported in runtime errors.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ValueType {
+    Nil,
+    Bool,
+    Number,
+    String,
+    Native,
+}
+
+impl Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::Nil => write!(f, "nil"),
+            ValueType::Bool => write!(f, "boolean"),
+            ValueType::Number => write!(f, "number"),
+            ValueType::String => write!(f, "string"),
+            ValueType::Native => write!(f, "native function"),
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+pub use crate::nanbox::Value;
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        if let Some(object) = self.as_obj() {
+            match object.get_otype() {
+                ObjectType::String => ValueType::String,
+                ObjectType::Native => ValueType::Native,
+            }
+        } else if self.as_num().is_some() {
+            ValueType::Number
+        } else if self.as_bool().is_some() {
+            ValueType::Bool
+        } else {
+            ValueType::Nil
+        }
+    }
+
+    /// Lox truthiness: `nil` and `false` are falsey, every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || self.as_bool() == Some(false)
+    }
+}
+
+#[cfg(not(feature = "nan-boxing"))]
 impl Value {
+    pub fn nil() -> Value {
+        Value::Nil
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
     pub fn as_num(&self) -> Option<f64> {
         match self {
             &Value::Number(val) => Some(val),
@@ -63,18 +140,21 @@ impl Value {
     }
 }
 
+#[cfg(not(feature = "nan-boxing"))]
 impl From<f64> for Value {
     fn from(value: f64) -> Self {
         Value::Number(value)
     }
 }
 
+#[cfg(not(feature = "nan-boxing"))]
 impl From<bool> for Value {
     fn from(value: bool) -> Self {
         Value::Bool(value)
     }
 }
 
+#[cfg(not(feature = "nan-boxing"))]
 impl From<Object> for Value {
     fn from(value: Object) -> Self {
         Value::Obj(value)
@@ -83,35 +163,104 @@ impl From<Object> for Value {
 
 impl Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Value::Nil => write!(f, "nil"),
-            Value::Bool(true) => write!(f, "true"),
-            Value::Bool(false) => write!(f, "false"),
-            Value::Number(number) => {
-                let stringified = number.to_string();
-                match stringified.strip_suffix(".0") {
-                    Some(integer) => write!(f, "{}", integer),
-                    None => write!(f, "{}", stringified),
-                }
+        if let Some(number) = self.as_num() {
+            let stringified = number.to_string();
+            match stringified.strip_suffix(".0") {
+                Some(integer) => write!(f, "{}", integer),
+                None => write!(f, "{}", stringified),
             }
-            Value::Obj(object) => write!(f, "{}", object),
+        } else if let Some(boolean) = self.as_bool() {
+            write!(f, "{}", boolean)
+        } else if let Some(object) = self.as_obj() {
+            write!(f, "{}", object)
+        } else {
+            write!(f, "nil")
+        }
+    }
+}
+
+/// Where a token or instruction comes from in the source text.
+///
+/// `line` and `column` are 1-based, with `column` counted in characters;
+/// `offset` and `len` are in bytes. Spans built from a bare line number
+/// (as in hand-assembled chunks) have column, offset and length 0.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SourceSpan {
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl From<usize> for SourceSpan {
+    fn from(line: usize) -> Self {
+        SourceSpan {
+            line,
+            ..Default::default()
+        }
+    }
+}
+
+/// The names of a chunk's global variables, indexed by slot. The natives
+/// take the first slots, in `NATIVES` order. The VM stores globals by slot
+/// and only looks up a name to report an undefined variable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Globals {
+    names: Vec<String>,
+}
+
+impl Default for Globals {
+    fn default() -> Self {
+        Globals {
+            names: NATIVES.iter().map(|(name, ..)| name.to_string()).collect(),
+        }
+    }
+}
+
+impl Globals {
+    /// The slot of `name`, which gets the next one unless it has a slot
+    /// already. `None` once all 256 slots are taken.
+    pub fn slot(&mut self, name: &str) -> Option<u8> {
+        if let Some(slot) = self.names.iter().position(|known| known == name) {
+            return Some(slot as u8);
         }
+
+        let slot = u8::try_from(self.names.len()).ok()?;
+        self.names.push(name.to_string());
+        Some(slot)
+    }
+
+    pub fn name(&self, slot: u8) -> Option<&str> {
+        self.names.get(slot as usize).map(String::as_str)
+    }
+
+    /// All names, the natives first.
+    pub fn names(&self) -> &[String] {
+        &self.names
     }
 }
 
 pub struct Chunk {
     pub code: Vec<Op>,
-    pub debug_info: Vec<usize>,
+    pub debug_info: Vec<SourceSpan>,
     pub constants: Vec<Value>,
+    pub globals: Globals,
     pub allocations: LinkedList<GcHandle>,
 }
 
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: Vec::new(),
             debug_info: Vec::new(),
             constants: Vec::new(),
+            globals: Globals::default(),
             allocations: LinkedList::new(),
         }
     }
@@ -124,19 +273,30 @@ impl Chunk {
     ) -> Self {
         Chunk {
             code,
-            debug_info,
+            debug_info: debug_info.into_iter().map(SourceSpan::from).collect(),
             constants,
+            globals: Globals::default(),
             allocations,
         }
     }
 
+    /// Same code, constants and globals. Numbers compare by their bits, so
+    /// that NaN constants are equal to themselves.
     pub fn instr_eq(&self, other: &Chunk) -> bool {
-        self.code == other.code && self.constants == other.constants
+        let same_constant = |(a, b): (&Value, &Value)| match (a.as_num(), b.as_num()) {
+            (Some(a), Some(b)) => a.to_bits() == b.to_bits(),
+            _ => a == b,
+        };
+
+        self.code == other.code
+            && self.globals == other.globals
+            && self.constants.len() == other.constants.len()
+            && self.constants.iter().zip(&other.constants).all(same_constant)
     }
 
-    pub fn add_op(&mut self, op: Op, line: usize) -> &mut Self {
+    pub fn add_op(&mut self, op: Op, span: impl Into<SourceSpan>) -> &mut Self {
         self.code.push(op);
-        self.debug_info.push(line);
+        self.debug_info.push(span.into());
 
         self
     }
@@ -146,22 +306,13 @@ impl Chunk {
         self
     }
 
-    pub fn add_constant(&mut self, value: Value, line: usize) -> &mut Self {
+    pub fn add_constant(&mut self, value: Value, span: impl Into<SourceSpan>) -> &mut Self {
+        let offset = self.constants.len() as u8;
         self.constants.push(value);
-        self.add_op(
-            Op::Constant {
-                offset: self.constants.len() as u8 - 1,
-            },
-            line,
-        )
+        self.add_op(Op::Constant { offset }, span)
     }
 }
 
-pub struct NamedChunk {
-    name: String,
-    chunk: Chunk,
-}
-
 impl fmt::Debug for Chunk {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         writeln!(f, "-*-*- Chunk @ {:p} -*-*-", self)?;
@@ -181,13 +332,6 @@ impl fmt::Debug for Chunk {
     }
 }
 
-impl fmt::Debug for NamedChunk {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        writeln!(f, "-*-*- {} -*-*-", self.name)?;
-        write!(f, "{:?}", self.chunk)
-    }
-}
-
 pub struct TraceInfo<'a> {
     pub offset: usize,
     pub op: Op,
@@ -202,9 +346,9 @@ impl fmt::Debug for TraceInfo<'_> {
 
         write!(f, "{:04}  ", offset)?;
 
-        let line = chunk.debug_info[offset];
+        let line = chunk.debug_info[offset].line;
 
-        if offset > 0 && chunk.debug_info[offset - 1] == line {
+        if offset > 0 && chunk.debug_info[offset - 1].line == line {
             write!(f, "   |  ")
         } else {
             write!(f, "{:4}  ", line)
@@ -222,9 +366,8 @@ impl fmt::Debug for TraceInfo<'_> {
     }
 }
 
+#[cfg(test)]
 mod tests {
-
-
     #[test]
     fn string_value_equality() {
         use crate::bc::Value;
@@ -239,10 +382,28 @@ mod tests {
         let v1 = Value::from(o1.get_object());
         let v2 = Value::from(o2.get_object());
         let v3 = Value::from(o3.get_object());
-        let v4 = v2.clone();
+        let v4 = v2;
 
         assert_ne!(v1, v2);
         assert_eq!(v2, v3);
         assert_eq!(v2, v4);
     }
+
+    #[test]
+    fn falsey_values() {
+        use crate::bc::Value;
+        use crate::gc::GC;
+
+        let empty = GC::new_string("");
+        let native = GC::new_native("f", 0, |_, _| Ok(Value::nil()));
+
+        assert!(Value::nil().is_falsey());
+        assert!(Value::from(false).is_falsey());
+
+        assert!(!Value::from(true).is_falsey());
+        assert!(!Value::from(0.0).is_falsey());
+        assert!(!Value::from(f64::NAN).is_falsey());
+        assert!(!Value::from(empty.get_object()).is_falsey());
+        assert!(!Value::from(native.get_object()).is_falsey());
+    }
 }
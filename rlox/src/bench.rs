@@ -0,0 +1,257 @@
+//! Timing Lox programs for `rlox bench`, and the baseline files that keep
+//! earlier timings to compare against.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::bc::Chunk;
+use crate::diagnostics::json_string;
+use crate::lc;
+use crate::opt;
+use crate::vm::VM;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Measurement {
+    pub name: String,
+    pub min: Duration,
+    pub median: Duration,
+    /// Instructions a single run executes.
+    pub instructions: u64,
+}
+
+/// Why a benchmark has no measurement.
+#[derive(Debug, PartialEq)]
+pub enum Failure {
+    /// The benchmark uses a part of Lox that rlox does not compile yet.
+    Unsupported(String),
+    /// The benchmark did not compile or run.
+    Error(String),
+}
+
+/// Compiles `source` once and runs it `runs` times, each time on a new VM
+/// with the output discarded. Fails with the first compile or runtime error.
+pub fn measure(name: &str, source: &str, runs: usize, options: opt::Options) -> Result<Measurement, Failure> {
+    let mut chunk = Chunk::new();
+    if let Some(error) = lc::compile_with(source, &mut chunk, options).first() {
+        return Err(match error.is_unsupported() {
+            true => Failure::Unsupported(error.to_string()),
+            false => Failure::Error(error.to_string()),
+        });
+    }
+
+    let mut times = Vec::with_capacity(runs);
+    let mut instructions = 0;
+    for _ in 0..runs.max(1) {
+        let mut vm = VM::new();
+        let start = Instant::now();
+        let result = vm.run(&chunk, &mut io::sink());
+        times.push(start.elapsed());
+
+        result.map_err(|err| Failure::Error(err.kind.to_string()))?;
+        instructions = vm.instructions_executed();
+    }
+
+    times.sort();
+    Ok(Measurement {
+        name: name.to_string(),
+        min: times[0],
+        median: times[times.len() / 2],
+        instructions,
+    })
+}
+
+/// How `current` regressed from `baseline`: by executing more instructions,
+/// or by a minimum time more than `threshold` (a fraction, 0.1 for 10%) slower.
+pub fn regressions(current: &Measurement, baseline: &Measurement, threshold: f64) -> Vec<String> {
+    let mut regressions = Vec::new();
+
+    if current.instructions > baseline.instructions {
+        regressions.push(format!(
+            "{} instructions, baseline {}",
+            current.instructions, baseline.instructions
+        ));
+    }
+
+    let (min, baseline_min) = (current.min.as_secs_f64(), baseline.min.as_secs_f64());
+    if min > baseline_min * (1.0 + threshold) {
+        regressions.push(format!(
+            "min {}, baseline {} ({:+.0}%)",
+            format_duration(current.min),
+            format_duration(baseline.min),
+            (min / baseline_min - 1.0) * 100.0
+        ));
+    }
+
+    regressions
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+    if seconds < 1e-3 {
+        format!("{:.1}µs", seconds * 1e6)
+    } else if seconds < 1.0 {
+        format!("{:.2}ms", seconds * 1e3)
+    } else {
+        format!("{:.3}s", seconds)
+    }
+}
+
+/// The baseline file recording `measurements`, one benchmark per line.
+pub fn to_json(measurements: &[Measurement]) -> String {
+    let entries: Vec<String> = measurements
+        .iter()
+        .map(|measurement| {
+            format!(
+                "  {{\"name\":{},\"min_ns\":{},\"median_ns\":{},\"instructions\":{}}}",
+                json_string(&measurement.name),
+                measurement.min.as_nanos(),
+                measurement.median.as_nanos(),
+                measurement.instructions
+            )
+        })
+        .collect();
+
+    format!("{{\"benchmarks\":[\n{}\n]}}\n", entries.join(",\n"))
+}
+
+/// Reads a baseline file written by `to_json`.
+pub fn parse_baseline(json: &str) -> Result<Vec<Measurement>, String> {
+    let mut lines = json.lines();
+    if lines.next() != Some("{\"benchmarks\":[") {
+        return Err("not a baseline file".to_string());
+    }
+
+    let mut measurements = Vec::new();
+    for line in lines {
+        match line.trim() {
+            "]}" => return Ok(measurements),
+            "" => {}
+            entry => {
+                let entry = entry.strip_suffix(',').unwrap_or(entry);
+                measurements.push(parse_entry(entry).ok_or_else(|| format!("invalid benchmark: {}", entry))?);
+            }
+        }
+    }
+    Err("unterminated baseline".to_string())
+}
+
+/// One line of `to_json`, without its trailing comma.
+fn parse_entry(entry: &str) -> Option<Measurement> {
+    let (name, rest) = parse_string(entry.strip_prefix("{\"name\":")?)?;
+    let mut numbers = rest.strip_suffix('}')?.split(',').skip(1).map(|field| field.split_once(':'));
+    let mut number = |key: &str| match numbers.next()? {
+        Some((field, value)) if field == format!("\"{}\"", key) => value.parse::<u64>().ok(),
+        _ => None,
+    };
+
+    let measurement = Measurement {
+        name,
+        min: Duration::from_nanos(number("min_ns")?),
+        median: Duration::from_nanos(number("median_ns")?),
+        instructions: number("instructions")?,
+    };
+    numbers.next().is_none().then_some(measurement)
+}
+
+/// Inverse of `json_string`: the string at the start of `json`, and the rest.
+fn parse_string(json: &str) -> Option<(String, &str)> {
+    let mut chars = json.strip_prefix('"')?.char_indices();
+    let mut string = String::new();
+    while let Some((i, c)) = chars.next() {
+        let escaped = match c {
+            '"' => return Some((string, &json[i + 2..])),
+            '\\' => chars.next()?.1,
+            c => {
+                string.push(c);
+                continue;
+            }
+        };
+        string.push(match escaped {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let hex: String = (0..4).filter_map(|_| chars.next().map(|(_, c)| c)).collect();
+                char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+            }
+            c => c,
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(name: &str, min_ns: u64, instructions: u64) -> Measurement {
+        Measurement {
+            name: name.to_string(),
+            min: Duration::from_nanos(min_ns),
+            median: Duration::from_nanos(min_ns * 2),
+            instructions,
+        }
+    }
+
+    #[test]
+    fn baseline_round_trip() {
+        let measurements = vec![
+            measurement("fib.lox", 1_500, 42),
+            measurement("dir/\"odd\"\tname.lox", 0, 0),
+            measurement("caf\u{e9} \u{1}\\.lox", 1, 2),
+        ];
+        assert_eq!(parse_baseline(&to_json(&measurements)), Ok(measurements));
+        assert_eq!(parse_baseline(&to_json(&[])), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn invalid_baselines() {
+        for json in [
+            "",
+            "{}",
+            "{\"benchmarks\":[\n",
+            "{\"benchmarks\":[\n{\"name\":\"a\"}\n]}",
+            "{\"benchmarks\":[\n{\"name\":\"a\",\"min_ns\":-1,\"median_ns\":1,\"instructions\":1}\n]}",
+            "{\"benchmarks\":[\n{\"name\":\"a\",\"median_ns\":1,\"min_ns\":1,\"instructions\":1}\n]}",
+            "{\"benchmarks\":[\n{\"name\":\"a\",\"min_ns\":1,\"median_ns\":1,\"instructions\":1,\"x\":1}\n]}",
+            "{\"benchmarks\":[\n{\"name\":\"unterminated,\"min_ns\":1}\n]}",
+        ] {
+            assert!(parse_baseline(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn measures_instructions() {
+        let source = "var a = 1; { var b = a; print a + b; }";
+        let mut chunk = Chunk::new();
+        assert!(lc::compile(source, &mut chunk).is_empty());
+
+        let measurement = measure("straight", source, 3, opt::Options::default()).unwrap();
+        assert_eq!(measurement.instructions, chunk.code.len() as u64);
+        assert!(measurement.min <= measurement.median);
+
+        assert_eq!(
+            measure("broken", "print ;", 3, opt::Options::default()),
+            Err(Failure::Error("[line 1] Error at ';': Expect expression.".to_string()))
+        );
+        assert_eq!(
+            measure("failing", "print -nil;", 3, opt::Options::default()),
+            Err(Failure::Error("Operand must be a number.".to_string()))
+        );
+        assert_eq!(
+            measure("loop", "var i = 0;\nwhile (i < 10) i = i + 1;", 3, opt::Options::default()),
+            Err(Failure::Unsupported("[line 2] Error at 'while': Expect expression.".to_string()))
+        );
+    }
+
+    #[test]
+    fn detects_regressions() {
+        let baseline = measurement("a", 1_000, 100);
+        assert!(regressions(&measurement("a", 1_050, 100), &baseline, 0.1).is_empty());
+        assert!(regressions(&measurement("a", 500, 90), &baseline, 0.1).is_empty());
+        assert_eq!(
+            regressions(&measurement("a", 1_500, 101), &baseline, 0.1),
+            vec!["101 instructions, baseline 100", "min 1.5µs, baseline 1.0µs (+50%)"]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Emits bytecode for a resolved program, see `ast`.
+
+use std::borrow::Cow;
+
+use crate::ast::{self, BinaryOp, Binding, Expr, Literal, Stmt, UnaryOp};
+use crate::bc::{Chunk, Op};
+use crate::lc::{self, ParseError, Strings, Token};
+use crate::opt;
+use crate::resolver;
+
+type Result<'src, T> = std::result::Result<T, ParseError<'src>>;
+
+/// Compiles `source` like `lc::compile`, but in separate passes: parsing,
+/// resolving and code generation. Each pass only runs if the previous one
+/// found no errors.
+pub fn compile<'src>(source: &'src str, chunk: &mut Chunk) -> Vec<ParseError<'src>> {
+    compile_with(source, chunk, opt::Options::default())
+}
+
+/// `compile` with some optimizations turned off, like `lc::compile_with`.
+pub fn compile_with<'src>(source: &'src str, chunk: &mut Chunk, options: opt::Options) -> Vec<ParseError<'src>> {
+    let mut statements = match ast::parse(source) {
+        Ok(statements) => statements,
+        Err(errors) => return errors,
+    };
+
+    let errors = resolver::resolve(&mut statements);
+    if !errors.is_empty() {
+        return errors;
+    }
+
+    let errors = generate(&statements, chunk);
+    if errors.is_empty() {
+        opt::optimize(chunk, options);
+    }
+    errors
+}
+
+/// Appends the code for `statements`, which must have been resolved.
+pub fn generate<'src>(statements: &[Stmt<'src>], chunk: &mut Chunk) -> Vec<ParseError<'src>> {
+    let mut generator = Generator {
+        chunk,
+        strings: Strings::default(),
+        errors: Vec::new(),
+    };
+
+    generator.statements(statements);
+    generator.errors
+}
+
+struct Generator<'src, 'c> {
+    chunk: &'c mut Chunk,
+    strings: Strings<'src>,
+    errors: Vec<ParseError<'src>>,
+}
+
+impl<'src> Generator<'src, '_> {
+    fn add_string(&mut self, string: impl Into<Cow<'src, str>>, location: &Token<'src>) -> Result<'src, u8> {
+        self.strings
+            .intern(string, &mut self.chunk.constants, &mut self.chunk.allocations, location)
+    }
+
+    /// Like `lc::Parser::declaration`, an error ends its statement but not the ones after it.
+    fn statements(&mut self, statements: &[Stmt<'src>]) {
+        for stmt in statements {
+            if let Err(err) = self.statement(stmt) {
+                self.errors.push(err);
+            }
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt<'src>) -> Result<'src, ()> {
+        match stmt {
+            Stmt::Expression { expression, semicolon } => {
+                self.expression(expression)?;
+                self.chunk.add_op(Op::Pop, semicolon.source_span());
+            }
+            Stmt::Print { keyword, expression } => {
+                self.expression(expression)?;
+                self.chunk.add_op(Op::Print, keyword.source_span());
+            }
+            Stmt::Var { keyword, name, initializer, binding } => {
+                let global_slot = match binding {
+                    Binding::Global => Some(lc::global_slot(&mut self.chunk.globals, name)?),
+                    Binding::Local(_) => None,
+                };
+
+                match initializer {
+                    Some(initializer) => self.expression(initializer)?,
+                    None => {
+                        self.chunk.add_op(Op::Nil, name.source_span());
+                    }
+                }
+
+                if let Some(slot) = global_slot {
+                    self.chunk.add_op(Op::DefineGlobal { slot }, keyword.source_span());
+                }
+            }
+            Stmt::Block { statements, right_brace } => {
+                self.statements(statements);
+
+                let locals = statements
+                    .iter()
+                    .filter(|stmt| matches!(stmt, Stmt::Var { binding: Binding::Local(_), .. }));
+                for _ in locals {
+                    self.chunk.add_op(Op::Pop, right_brace.source_span());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn expression(&mut self, expr: &Expr<'src>) -> Result<'src, ()> {
+        match expr {
+            Expr::Literal { value, token } => match value {
+                Literal::Nil => {
+                    self.chunk.add_op(Op::Nil, token.source_span());
+                }
+                Literal::Bool(true) => {
+                    self.chunk.add_op(Op::True, token.source_span());
+                }
+                Literal::Bool(false) => {
+                    self.chunk.add_op(Op::False, token.source_span());
+                }
+                Literal::Number(value) => {
+                    lc::next_constant(&self.chunk.constants, token)?;
+                    self.chunk.add_constant((*value).into(), token.source_span());
+                }
+                Literal::String(string) => {
+                    let offset = self.add_string(string.clone(), token)?;
+                    self.chunk.add_op(Op::Constant { offset }, token.source_span());
+                }
+            },
+            Expr::Grouping(inner) => self.expression(inner)?,
+            Expr::Unary { op, token, operand } => {
+                self.expression(operand)?;
+                let op = match op {
+                    UnaryOp::Negate => Op::Negate,
+                    UnaryOp::Not => Op::Not,
+                };
+                self.chunk.add_op(op, token.source_span());
+            }
+            Expr::Binary { op, token, left, right } => {
+                self.expression(left)?;
+                self.expression(right)?;
+
+                let span = token.source_span();
+                match op {
+                    BinaryOp::Add => self.chunk.add_op(Op::Add, span),
+                    BinaryOp::Subtract => self.chunk.add_op(Op::Subtract, span),
+                    BinaryOp::Multiply => self.chunk.add_op(Op::Multiply, span),
+                    BinaryOp::Divide => self.chunk.add_op(Op::Divide, span),
+                    BinaryOp::Equal => self.chunk.add_op(Op::Equal, span),
+                    BinaryOp::Greater => self.chunk.add_op(Op::Greater, span),
+                    BinaryOp::Less => self.chunk.add_op(Op::Less, span),
+                    BinaryOp::NotEqual => self.chunk.add_op(Op::NotEqual, span),
+                    BinaryOp::GreaterEqual => self.chunk.add_op(Op::GreaterEqual, span),
+                    BinaryOp::LessEqual => self.chunk.add_op(Op::LessEqual, span),
+                };
+            }
+            Expr::Variable { name, binding } => {
+                let op = match *binding {
+                    Binding::Local(offset) => Op::GetLocal { offset },
+                    Binding::Global => Op::GetGlobal { slot: lc::global_slot(&mut self.chunk.globals, name)? },
+                };
+                self.chunk.add_op(op, name.source_span());
+            }
+            Expr::Assign { name, value, binding } => {
+                let op = match *binding {
+                    Binding::Local(offset) => Op::SetLocal { offset },
+                    Binding::Global => Op::SetGlobal { slot: lc::global_slot(&mut self.chunk.globals, name)? },
+                };
+                self.expression(value)?;
+                self.chunk.add_op(op, name.source_span());
+            }
+            Expr::Call { callee, paren, arguments } => {
+                self.expression(callee)?;
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+                // The parser rejects calls with more than 255 arguments.
+                let arg_count = arguments.len() as u8;
+                self.chunk.add_op(Op::Call { arg_count }, paren.source_span());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lc;
+
+    /// Compiles `source` with both compilers and checks that they agree.
+    /// Compares the compilers with and without superinstructions.
+    fn assert_same_as_single_pass(source: &str) {
+        for superinstructions in [true, false] {
+            let options = opt::Options { superinstructions };
+            let mut single_pass = Chunk::new();
+            let single_pass_errors = lc::compile_with(source, &mut single_pass, options);
+            let mut multi_pass = Chunk::new();
+            let multi_pass_errors = compile_with(source, &mut multi_pass, options);
+
+            assert_eq!(multi_pass_errors, single_pass_errors, "{}", source);
+            if single_pass_errors.is_empty() {
+                assert!(multi_pass.instr_eq(&single_pass), "{}\n{:?}\n{:?}", source, multi_pass, single_pass);
+                assert_eq!(multi_pass.debug_info, single_pass.debug_info, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn same_bytecode_as_single_pass() {
+        let sources = [
+            "",
+            "print 1 + 2 * -3 / (4 - 5);",
+            "print !true == false != nil; print 1 < 2; print 1 <= 2; print 1 > 2; print 1 >= 2;",
+            "var a; var b = \"b\"; a = b = \"a\\u{e9}\"; print a + b + \"b\";",
+            "var a = 1; { var a = a + 1; { var b = a; a = b * 2; print a; } print a; } print a;",
+            "{ var a; var b; { var c = 3; b = c; } a = b; }",
+            "print argv(0) + getenv(\"X\"); print argc(); print argv(0 + 0);",
+            "{ var f = getenv; print f(\"X\"); } var unused = f;",
+        ];
+
+        for source in sources {
+            assert_same_as_single_pass(source);
+        }
+    }
+
+    #[test]
+    fn same_scope_errors_as_single_pass() {
+        let sources = [
+            "{ var a = 1; var a = 2; print a; }",
+            "{ var a = a; print 1; } { var b; { var b = b + b; } }",
+            "var a = a; { var a = 1; { var a = a; } }",
+            "print f(1, 2;",
+        ];
+
+        for source in sources {
+            assert_same_as_single_pass(source);
+        }
+    }
+
+    #[test]
+    fn too_many_locals_and_constants() {
+        let locals: String = (0..300).map(|i| format!("var v{} = {};", i, i)).collect();
+        assert_same_as_single_pass(&format!("{{{}}}", locals));
+        assert_same_as_single_pass(&locals);
+    }
+
+    #[test]
+    fn resolves_slots() {
+        let mut statements = ast::parse("var g; { var a; { var b = a; g = b; } var c = g; }").unwrap();
+        assert!(resolver::resolve(&mut statements).is_empty());
+
+        let [Stmt::Var { binding: Binding::Global, .. }, Stmt::Block { statements, .. }] = &statements[..] else {
+            panic!("{:?}", statements);
+        };
+        let [Stmt::Var { binding: Binding::Local(0), .. }, Stmt::Block { statements: inner, .. }, Stmt::Var { binding: Binding::Local(1), .. }] = &statements[..] else {
+            panic!("{:?}", statements);
+        };
+        let [Stmt::Var { binding: Binding::Local(1), initializer: Some(Expr::Variable { binding: Binding::Local(0), .. }), .. }, Stmt::Expression { expression: Expr::Assign { binding: Binding::Global, value, .. }, .. }] = &inner[..] else {
+            panic!("{:?}", inner);
+        };
+        assert!(matches!(**value, Expr::Variable { binding: Binding::Local(1), .. }));
+    }
+}
@@ -0,0 +1,345 @@
+use std::fmt::Write;
+
+use crate::bc::SourceSpan;
+
+/// How errors are written to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// The one-line `[line N] Error ...` format of the reference implementation.
+    #[default]
+    Short,
+    /// The message followed by the offending source lines, with the spans underlined.
+    Rich,
+    /// One JSON object per line, for tools.
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "short" => Some(ErrorFormat::Short),
+            "rich" => Some(ErrorFormat::Rich),
+            "json" => Some(ErrorFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: SourceSpan,
+    pub message: String,
+    pub primary: bool,
+}
+
+/// Whether an error was found while compiling or while running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Compile,
+    Runtime,
+}
+
+/// An error message with the source locations it refers to.
+///
+/// `code` identifies the kind of error and never changes once assigned, so
+/// tools can rely on it instead of the message text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub stage: Stage,
+    pub code: &'static str,
+    pub message: String,
+    pub labels: Vec<Label>,
+}
+
+impl Diagnostic {
+    pub fn new(stage: Stage, code: &'static str, message: String, span: SourceSpan) -> Self {
+        Diagnostic {
+            stage,
+            code,
+            message,
+            labels: vec![Label {
+                span,
+                message: String::new(),
+                primary: true,
+            }],
+        }
+    }
+
+    /// Adds a secondary label, unless it would underline the primary span again.
+    pub fn with_label(mut self, span: SourceSpan, message: &str) -> Self {
+        if self.labels.iter().any(|label| label.primary && label.span == span) {
+            return self;
+        }
+
+        self.labels.push(Label {
+            span,
+            message: message.to_string(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn render(&self, source: &str, path: &str, color: bool) -> String {
+        let style = Style::new(color);
+        let mut out = String::new();
+
+        let header = format!("error[{}]", self.code);
+        let _ = writeln!(out, "{}: {}", style.error(&header), style.bold(&self.message));
+
+        let Some(primary) = self.labels.iter().find(|label| label.primary) else {
+            return out;
+        };
+
+        let mut labels: Vec<&Label> = self.labels.iter().collect();
+        labels.sort_by_key(|label| (label.span.line, label.span.column, !label.primary));
+
+        let width = labels
+            .iter()
+            .map(|label| label.span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = style.gutter(&format!("{:width$} |", ""));
+
+        let _ = write!(out, "{:width$}{} {}:{}", "", style.gutter("-->"), path, primary.span.line);
+        if primary.span.column > 0 {
+            let _ = write!(out, ":{}", primary.span.column);
+        }
+        let _ = writeln!(out, "\n{}", gutter);
+
+        let mut previous_line = None;
+        for label in labels {
+            let line_number = label.span.line;
+            let Some(line) = source.split('\n').nth(line_number.wrapping_sub(1)) else {
+                continue;
+            };
+            let line = line.strip_suffix('\r').unwrap_or(line);
+
+            if previous_line != Some(line_number) {
+                let number = style.gutter(&format!("{:>width$} |", line_number));
+                let _ = writeln!(out, "{} {}", number, line);
+                previous_line = Some(line_number);
+            }
+
+            if label.span.column == 0 {
+                continue;
+            }
+
+            let marker = if label.primary { "^" } else { "-" };
+            let underline = format!(
+                "{}{}",
+                marker.repeat(underline_width(source, line, &label.span)),
+                if label.message.is_empty() {
+                    String::new()
+                } else {
+                    format!(" {}", label.message)
+                }
+            );
+            let underline = if label.primary {
+                style.error(&underline)
+            } else {
+                style.gutter(&underline)
+            };
+
+            let _ = writeln!(out, "{} {}{}", gutter, indentation(line, label.span.column), underline);
+        }
+
+        out
+    }
+
+    /// A single line of JSON. The primary span's fields are top-level; secondary labels go in `labels`.
+    pub fn to_json(&self, path: &str) -> String {
+        let span_fields = |span: &SourceSpan| {
+            format!(
+                "\"line\":{},\"column\":{},\"offset\":{},\"length\":{}",
+                span.line, span.column, span.offset, span.len
+            )
+        };
+
+        let primary = self
+            .labels
+            .iter()
+            .find(|label| label.primary)
+            .map(|label| label.span)
+            .unwrap_or_default();
+
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .filter(|label| !label.primary)
+            .map(|label| format!("{{{},\"message\":{}}}", span_fields(&label.span), json_string(&label.message)))
+            .collect();
+
+        let stage = match self.stage {
+            Stage::Compile => "compile",
+            Stage::Runtime => "runtime",
+        };
+
+        format!(
+            "{{\"stage\":\"{}\",\"code\":\"{}\",\"message\":{},\"file\":{},{},\"labels\":[{}]}}",
+            stage,
+            self.code,
+            json_string(&self.message),
+            json_string(path),
+            span_fields(&primary),
+            labels.join(",")
+        )
+    }
+}
+
+pub(crate) fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Whitespace up to the given column, keeping tabs so the underline lines up.
+fn indentation(line: &str, column: usize) -> String {
+    line.chars()
+        .take(column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect()
+}
+
+/// Width of the span in characters, clamped to the end of its first line and at least 1.
+fn underline_width(source: &str, line: &str, span: &SourceSpan) -> usize {
+    let text = source
+        .get(span.offset..span.offset + span.len)
+        .unwrap_or_default();
+    let text = text.split('\n').next().unwrap_or_default();
+    let remaining = line.chars().count().saturating_sub(span.column - 1);
+
+    text.chars().count().min(remaining).max(1)
+}
+
+struct Style {
+    color: bool,
+}
+
+impl Style {
+    fn new(color: bool) -> Self {
+        Style { color }
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn error(&self, text: &str) -> String {
+        self.paint("1;31", text)
+    }
+
+    fn gutter(&self, text: &str) -> String {
+        self.paint("1;34", text)
+    }
+
+    fn bold(&self, text: &str) -> String {
+        self.paint("1", text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_primary_and_secondary_labels() {
+        let source = "{\n  var a = 1;\n  var a = 2;\n}";
+        let previous = SourceSpan { line: 2, column: 7, offset: 8, len: 1 };
+        let duplicate = SourceSpan { line: 3, column: 7, offset: 21, len: 1 };
+        let diagnostic = Diagnostic::new(Stage::Compile, "E0110", "Already a variable with this name in this scope.".to_string(), duplicate)
+            .with_label(previous, "previous declaration here");
+
+        assert_eq!(
+            diagnostic.render(source, "test.lox", false),
+            "error[E0110]: Already a variable with this name in this scope.\n\
+             \x20--> test.lox:3:7\n\
+             \x20 |\n\
+             2 |   var a = 1;\n\
+             \x20 |       - previous declaration here\n\
+             3 |   var a = 2;\n\
+             \x20 |       ^\n"
+        );
+    }
+
+    #[test]
+    fn render_labels_on_one_line() {
+        let source = "var a = 1; a = a + nil;";
+        let nil = SourceSpan { line: 1, column: 20, offset: 19, len: 3 };
+        let diagnostic = Diagnostic::new(Stage::Runtime, "E0200", "Operands must be two numbers or two strings.".to_string(), nil)
+            .with_label(SourceSpan { line: 1, column: 16, offset: 15, len: 1 }, "number")
+            .with_label(nil, "called from here");
+
+        assert_eq!(diagnostic.labels.len(), 2);
+        assert_eq!(
+            diagnostic.render(source, "t.lox", false),
+            "error[E0200]: Operands must be two numbers or two strings.\n\
+             \x20--> t.lox:1:20\n\
+             \x20 |\n\
+             1 | var a = 1; a = a + nil;\n\
+             \x20 |                - number\n\
+             \x20 |                    ^^^\n"
+        );
+    }
+
+    #[test]
+    fn render_multi_character_span_and_color() {
+        let source = "print \"a\" + nil;";
+        let span = SourceSpan { line: 1, column: 13, offset: 12, len: 3 };
+        let diagnostic = Diagnostic::new(Stage::Compile, "E0101", "Expect expression.".to_string(), span);
+
+        let plain = diagnostic.render(source, "t.lox", false);
+        assert!(plain.ends_with("1 | print \"a\" + nil;\n  |             ^^^\n"));
+
+        let colored = diagnostic.render(source, "t.lox", true);
+        assert!(colored.starts_with("\x1b[1;31merror[E0101]\x1b[0m: "));
+    }
+
+    #[test]
+    fn render_line_only_span() {
+        let diagnostic = Diagnostic::new(Stage::Runtime, "E0204", "Stack underflow.".to_string(), SourceSpan::from(2));
+
+        assert_eq!(
+            diagnostic.render("1;\n2;", "t.lox", false),
+            "error[E0204]: Stack underflow.\n --> t.lox:2\n  |\n2 | 2;\n"
+        );
+    }
+
+    #[test]
+    fn json_line() {
+        let span = SourceSpan { line: 3, column: 7, offset: 21, len: 1 };
+        let previous = SourceSpan { line: 2, column: 7, offset: 8, len: 1 };
+        let diagnostic = Diagnostic::new(Stage::Compile, "E0110", "Already declared.".to_string(), span)
+            .with_label(previous, "previous declaration here");
+
+        assert_eq!(
+            diagnostic.to_json("dir/a \"b\".lox"),
+            "{\"stage\":\"compile\",\"code\":\"E0110\",\"message\":\"Already declared.\",\
+             \"file\":\"dir/a \\\"b\\\".lox\",\"line\":3,\"column\":7,\"offset\":21,\"length\":1,\
+             \"labels\":[{\"line\":2,\"column\":7,\"offset\":8,\"length\":1,\
+             \"message\":\"previous declaration here\"}]}"
+        );
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("a\\b\n\t\u{1}\u{e9}"), "\"a\\\\b\\n\\t\\u0001\u{e9}\"");
+    }
+}
@@ -0,0 +1,295 @@
+//! Fuzz entry points, shared by the cargo-fuzz targets in `fuzz/` and the
+//! offline corpus driver in `tests/fuzz.rs`.
+//!
+//! Every entry point accepts arbitrary bytes; a panic or crash is a bug.
+
+use std::collections::LinkedList;
+use std::io;
+
+use crate::bc::{Chunk, Globals, Op, Value};
+use crate::codegen;
+use crate::gc::{ObjString, GC};
+use crate::lc;
+use crate::natives::NATIVES;
+use crate::vm::VM;
+
+/// Inputs longer than this are ignored by `run`.
+pub const MAX_RUN_INPUT: usize = 64 * 1024;
+
+/// Instructions a fuzzed program may execute, so that inputs which never
+/// finish are reported as errors rather than hangs.
+pub const RUN_BUDGET: u64 = 1_000_000;
+
+fn budgeted_vm() -> VM {
+    let mut vm = VM::new();
+    vm.set_instruction_budget(Some(RUN_BUDGET));
+    vm
+}
+
+/// Compiles the input as (lossily decoded) source text.
+pub fn compile(data: &[u8]) {
+    let source = String::from_utf8_lossy(data);
+    let _ = lc::compile(&source, &mut Chunk::new());
+}
+
+/// Compiles the input with both `lc::compile` and `codegen::compile`, and
+/// panics if they disagree on whether it compiles or on the bytecode.
+pub fn compilers(data: &[u8]) {
+    let source = String::from_utf8_lossy(data);
+    let mut single_pass = Chunk::new();
+    let single_pass_ok = lc::compile(&source, &mut single_pass).is_empty();
+    let mut multi_pass = Chunk::new();
+    let multi_pass_ok = codegen::compile(&source, &mut multi_pass).is_empty();
+
+    assert_eq!(single_pass_ok, multi_pass_ok, "only one compiler accepts the input");
+    if single_pass_ok {
+        assert!(single_pass.instr_eq(&multi_pass), "the compilers disagree on the bytecode");
+        assert_eq!(single_pass.debug_info, multi_pass.debug_info, "the compilers disagree on the spans");
+    }
+}
+
+/// Compiles the input and, if it has no errors, runs it with output discarded.
+pub fn run(data: &[u8]) {
+    if data.len() > MAX_RUN_INPUT {
+        return;
+    }
+
+    let source = String::from_utf8_lossy(data);
+    let mut chunk = Chunk::new();
+    if lc::compile(&source, &mut chunk).is_empty() {
+        let _ = budgeted_vm().run(&chunk, &mut io::sink());
+    }
+}
+
+/// Runs the input decoded as a raw chunk, see `decode_chunk`.
+pub fn chunk(data: &[u8]) {
+    let chunk = decode_chunk(data);
+    let _ = budgeted_vm().run(&chunk, &mut io::sink());
+}
+
+/// Encoded chunk of the compiled source, to seed the `chunk` target.
+pub fn chunk_seed(data: &[u8]) -> Option<Vec<u8>> {
+    let source = String::from_utf8_lossy(data);
+    let mut chunk = Chunk::new();
+    if lc::compile(&source, &mut chunk).is_empty() {
+        Some(encode_chunk(&chunk))
+    } else {
+        None
+    }
+}
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    /// The next byte; missing bytes read as zero.
+    fn byte(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((&byte, rest)) => {
+                self.data = rest;
+                byte
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Builds a chunk from bytes, accepting any input.
+///
+/// The first byte is the number of constants. Each constant is a tag byte
+/// followed by its payload: nothing for nil, one byte for a boolean, eight
+/// little-endian bytes for a number, and a length byte plus that many bytes
+/// for a string. The next byte is the number of global names after the
+/// natives', each a length byte plus that many bytes. The remaining bytes
+/// are instructions, each an opcode byte followed by its operand bytes, see
+/// `encode_op`.
+pub fn decode_chunk(data: &[u8]) -> Chunk {
+    let mut reader = Reader { data };
+    let mut constants = Vec::new();
+    let mut allocations = LinkedList::new();
+
+    for _ in 0..reader.byte() {
+        let value = match reader.byte() % 4 {
+            TAG_NIL => Value::nil(),
+            TAG_BOOL => Value::from(reader.byte() & 1 == 1),
+            TAG_NUMBER => Value::from(f64::from_le_bytes([(); 8].map(|_| reader.byte()))),
+            _ => {
+                let content: Vec<u8> = (0..reader.byte()).map(|_| reader.byte()).collect();
+                let handle = GC::new_string(&String::from_utf8_lossy(&content));
+                let value = Value::from(handle.get_object());
+                allocations.push_front(handle);
+                value
+            }
+        };
+        constants.push(value);
+    }
+
+    let mut globals = Globals::default();
+    for _ in 0..reader.byte() {
+        let name: Vec<u8> = (0..reader.byte()).map(|_| reader.byte()).collect();
+        globals.slot(&String::from_utf8_lossy(&name));
+    }
+
+    let mut code = Vec::new();
+    while !reader.data.is_empty() {
+        code.push(decode_op(&mut reader));
+    }
+
+    let lines = (1..=code.len()).collect();
+    let mut chunk = Chunk::new_with(code, lines, constants, allocations);
+    chunk.globals = globals;
+    chunk
+}
+
+/// Inverse of `decode_chunk`. Only the first 255 constants and 255 bytes
+/// of each string and global name are kept.
+pub fn encode_chunk(chunk: &Chunk) -> Vec<u8> {
+    let constants = &chunk.constants[..chunk.constants.len().min(255)];
+    let mut bytes = vec![constants.len() as u8];
+
+    for value in constants {
+        let string = value.as_obj().and_then(|object| object.downcast::<ObjString>());
+        if let Some(b) = value.as_bool() {
+            bytes.extend([TAG_BOOL, b as u8]);
+        } else if let Some(num) = value.as_num() {
+            bytes.push(TAG_NUMBER);
+            bytes.extend(num.to_le_bytes());
+        } else if let Some(string) = string {
+            let content = string.as_str().as_bytes();
+            let content = &content[..content.len().min(255)];
+            bytes.extend([TAG_STRING, content.len() as u8]);
+            bytes.extend(content);
+        } else {
+            bytes.push(TAG_NIL);
+        }
+    }
+
+    let names = &chunk.globals.names()[NATIVES.len()..];
+    bytes.push(names.len() as u8);
+    for name in names {
+        let name = &name.as_bytes()[..name.len().min(255)];
+        bytes.push(name.len() as u8);
+        bytes.extend(name);
+    }
+
+    for op in &chunk.code {
+        bytes.extend(encode_op(op));
+    }
+
+    bytes
+}
+
+/// Opcode followed by the operands of an instruction.
+fn encode_op(op: &Op) -> Vec<u8> {
+    match *op {
+        Op::Return => vec![0],
+        Op::Constant { offset } => vec![1, offset],
+        Op::Nil => vec![2],
+        Op::True => vec![3],
+        Op::False => vec![4],
+        Op::Not => vec![5],
+        Op::Negate => vec![6],
+        Op::Add => vec![7],
+        Op::Subtract => vec![8],
+        Op::Multiply => vec![9],
+        Op::Divide => vec![10],
+        Op::Equal => vec![11],
+        Op::Greater => vec![12],
+        Op::Less => vec![13],
+        Op::Print => vec![14],
+        Op::Pop => vec![15],
+        Op::DefineGlobal { slot } => vec![16, slot],
+        Op::GetGlobal { slot } => vec![17, slot],
+        Op::SetGlobal { slot } => vec![18, slot],
+        Op::GetLocal { offset } => vec![19, offset],
+        Op::SetLocal { offset } => vec![20, offset],
+        Op::Call { arg_count } => vec![21, arg_count],
+        Op::NotEqual => vec![22],
+        Op::GreaterEqual => vec![23],
+        Op::LessEqual => vec![24],
+        Op::GetLocal2 { first, second } => vec![25, first, second],
+        Op::AddLocalConst { slot, offset } => vec![26, slot, offset],
+        Op::IncrementLocal { slot, offset } => vec![27, slot, offset],
+    }
+}
+
+/// Inverse of `encode_op`; opcodes wrap around so every byte is an instruction.
+fn decode_op(reader: &mut Reader) -> Op {
+    let opcode = reader.byte();
+    let mut operand = || reader.byte();
+
+    match opcode % 28 {
+        0 => Op::Return,
+        1 => Op::Constant { offset: operand() },
+        2 => Op::Nil,
+        3 => Op::True,
+        4 => Op::False,
+        5 => Op::Not,
+        6 => Op::Negate,
+        7 => Op::Add,
+        8 => Op::Subtract,
+        9 => Op::Multiply,
+        10 => Op::Divide,
+        11 => Op::Equal,
+        12 => Op::Greater,
+        13 => Op::Less,
+        14 => Op::Print,
+        15 => Op::Pop,
+        16 => Op::DefineGlobal { slot: operand() },
+        17 => Op::GetGlobal { slot: operand() },
+        18 => Op::SetGlobal { slot: operand() },
+        19 => Op::GetLocal { offset: operand() },
+        20 => Op::SetLocal { offset: operand() },
+        21 => Op::Call { arg_count: operand() },
+        22 => Op::NotEqual,
+        23 => Op::GreaterEqual,
+        24 => Op::LessEqual,
+        25 => Op::GetLocal2 { first: operand(), second: operand() },
+        26 => Op::AddLocalConst { slot: operand(), offset: operand() },
+        _ => Op::IncrementLocal { slot: operand(), offset: operand() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_encoding_round_trip() {
+        let source = b"var a = \"one\"; { var b = -2.5; print a == b != true; b = nil; } print argc();";
+        let mut chunk = Chunk::new();
+        assert!(lc::compile(&String::from_utf8_lossy(source), &mut chunk).is_empty());
+
+        let encoded = chunk_seed(source).unwrap();
+        assert!(decode_chunk(&encoded).instr_eq(&chunk));
+        assert_eq!(encode_chunk(&decode_chunk(&encoded)), encoded);
+    }
+
+    #[test]
+    fn malformed_chunks_are_rejected() {
+        let cases: [&[u8]; 6] = [
+            // Constant 7 without constants.
+            &[0, 0, 1, 7],
+            // GetGlobal of a slot without a name.
+            &[0, 1, 1, b'a', 17, 5],
+            // GetGlobal of a global that was never defined.
+            &[0, 1, 1, b'a', 17, 4],
+            // GetLocal past the top of the stack.
+            &[0, 0, 19, 3],
+            // SetLocal on an empty stack.
+            &[0, 0, 2, 20, 0, 15, 20, 0],
+            // Call without a callee.
+            &[0, 0, 21, 9],
+        ];
+
+        for data in cases {
+            assert!(VM::new().run(&decode_chunk(data), &mut io::sink()).is_err(), "{:?}", data);
+        }
+    }
+}
@@ -6,8 +6,9 @@ use std::{
     fmt::{self, Display},
 };
 
-/// Api
+use crate::vm::NativeFn;
 
+/// Api
 pub struct GC {}
 
 impl GC {
@@ -19,6 +20,10 @@ impl GC {
         unsafe { concat_string(first, second) }.unwrap()
     }
 
+    pub fn new_native(name: &'static str, arity: u8, function: NativeFn) -> GcHandle {
+        unsafe { allocate_native(name, arity, function) }
+    }
+
     pub fn free(handle: GcHandle) {
         unsafe { deallocate_object(handle.object) }
     }
@@ -29,6 +34,7 @@ impl GC {
 #[repr(usize)]
 pub enum ObjectType {
     String,
+    Native,
 }
 
 pub(crate) trait IsObject {
@@ -40,14 +46,18 @@ pub(crate) trait IsObject {
 
 /// Object Hierarchy / Layout stuff
 ///
-/// Object
-///    |
-/// ObjString
+/// ```text
+///       Object
+///      /      \
+/// ObjString  ObjNative
 ///
 /// Object:    --ptr-to-->   [ [<otype>], .... data ....   ]
 /// ObjString: --ptr-to-->   [[[<otype>], len], ...data... ]
 ///                           ^-StringHeader-^
 ///                          ^----------StringAlloc--------^
+/// ObjNative: --ptr-to-->   [ [<otype>], name, arity, fn  ]
+///                          ^---------NativeAlloc---------^
+/// ```
 ///
 /// GcHandle owns the underlying memory and must not be dropped before the corresponding Objects are.
 
@@ -64,7 +74,7 @@ impl Drop for GcHandle {
 
 impl GcHandle {
     pub fn get_object(&self) -> Object {
-        return self.object;
+        self.object
     }
 }
 
@@ -92,6 +102,25 @@ impl IsObject for ObjString {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct ObjNative {
+    ptr: *mut NativeAlloc,
+}
+
+impl IsObject for ObjNative {
+    fn otype() -> ObjectType {
+        ObjectType::Native
+    }
+
+    fn from_object(object: Object) -> ObjNative {
+        ObjNative { ptr: object.ptr as *mut NativeAlloc }
+    }
+
+    fn upcast(self) -> Object {
+        Object { ptr: self.ptr as *mut Header }
+    }
+}
+
 #[repr(C)]
 struct Header {
     otype: ObjectType,
@@ -109,6 +138,14 @@ struct StringHeader {
     len: usize,
 }
 
+#[repr(C)]
+struct NativeAlloc {
+    object_header: Header,
+    name: &'static str,
+    arity: u8,
+    function: NativeFn,
+}
+
 
 const fn data_offset() -> usize {
     std::mem::size_of::<StringHeader>()
@@ -120,7 +157,9 @@ impl Display for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.get_otype() {
             ObjectType::String =>
-                fmt::Display::fmt(&self.downcast::<ObjString>().unwrap(), f)
+                fmt::Display::fmt(&self.downcast::<ObjString>().unwrap(), f),
+            ObjectType::Native =>
+                fmt::Display::fmt(&self.downcast::<ObjNative>().unwrap(), f),
         }
     }
 }
@@ -137,6 +176,18 @@ impl Object {
             None
         }
     }
+
+    /// The object's address, for packing it into a NaN-boxed `Value`.
+    #[cfg(feature = "nan-boxing")]
+    pub(crate) fn to_bits(self) -> u64 {
+        self.ptr as u64
+    }
+
+    /// Inverse of `to_bits`.
+    #[cfg(feature = "nan-boxing")]
+    pub(crate) fn from_bits(bits: u64) -> Object {
+        Object { ptr: bits as *mut Header }
+    }
 }
 
 impl fmt::Debug for Object {
@@ -146,6 +197,9 @@ impl fmt::Debug for Object {
                 let string = self.downcast::<ObjString>().unwrap().as_str();
                 write!(f, "STR {} {:?}", string.len(), &string[..8.min(string.len())])
             }
+            ObjectType::Native => {
+                write!(f, "NATIVE {}", self.downcast::<ObjNative>().unwrap().name())
+            }
         }
     }
 }
@@ -165,6 +219,7 @@ impl PartialEq for Object {
                 ObjectType::String => {
                     self.downcast::<ObjString>() == other.downcast::<ObjString>()
                 }
+                ObjectType::Native => false,
             }
         }
     }
@@ -188,17 +243,37 @@ impl ObjString {
         let (layout_, offset)  = StringAlloc::layout(length).unwrap();
         unsafe {
             std::slice::from_raw_parts(
-                (self.ptr as *mut u8).offset(offset as isize),
+                (self.ptr as *mut u8).add(offset),
                 length
             )
         }
     }
 
-    fn as_str<'a>(&self) -> &'a str {
+    pub(crate) fn as_str<'a>(&self) -> &'a str {
         unsafe { std::str::from_utf8_unchecked(self.as_slice()) }
     }
 }
 
+impl ObjNative {
+    pub fn name(&self) -> &'static str {
+        unsafe { (*self.ptr).name }
+    }
+
+    pub fn arity(&self) -> u8 {
+        unsafe { (*self.ptr).arity }
+    }
+
+    pub fn function(&self) -> NativeFn {
+        unsafe { (*self.ptr).function }
+    }
+}
+
+impl fmt::Display for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
 impl fmt::Display for ObjString {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Display::fmt(self.as_str(), f)
@@ -230,7 +305,7 @@ unsafe fn allocate_string_obj<'a>(
 ) -> Result<(GcHandle, &'a mut [u8]), LayoutError> {
     let (layout, offset) = StringAlloc::layout(length)?;
     let allocation = alloc(layout);
-    let data_ptr = allocation.offset(offset as isize);
+    let data_ptr = allocation.add(offset);
     let header = allocation as *mut StringHeader;
     (*header).len = length;
     (*header).object_header.otype = ObjectType::String;
@@ -259,6 +334,22 @@ unsafe fn concat_string(a: ObjString, b: ObjString) -> Result<GcHandle, LayoutEr
     Ok(gc_handle)
 }
 
+unsafe fn allocate_native(name: &'static str, arity: u8, function: NativeFn) -> GcHandle {
+    let native = alloc(Layout::new::<NativeAlloc>()) as *mut NativeAlloc;
+    native.write(NativeAlloc {
+        object_header: Header {
+            otype: ObjectType::Native,
+        },
+        name,
+        arity,
+        function,
+    });
+    let object = Object {
+        ptr: native as *mut Header,
+    };
+    GcHandle { object }
+}
+
 unsafe fn deallocate_object(object: Object) {
     match object.get_otype() {
         ObjectType::String => {
@@ -268,6 +359,9 @@ unsafe fn deallocate_object(object: Object) {
                 StringAlloc::layout((*header).len).unwrap().0,
             )
         }
+        ObjectType::Native => {
+            dealloc(object.ptr as *mut u8, Layout::new::<NativeAlloc>())
+        }
     }
 }
 
@@ -1,18 +1,64 @@
-use std::{collections::hash_map, fmt};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
 use std::iter::Peekable;
 use std::str::CharIndices;
-use std::collections::HashMap;
+use std::collections::{HashMap, LinkedList};
 
-use crate::bc::Value;
-use crate::{bc::{Chunk, Op}, gc::GC};
+use unicode_xid::UnicodeXID;
+
+use crate::bc::{Globals, SourceSpan, Value};
+use crate::diagnostics::{Diagnostic, Stage};
+use crate::ast::Binding;
+use crate::gc::GcHandle;
+use crate::resolver::Scopes;
+use crate::{bc::{Chunk, Op}, gc::GC, opt};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ScanErrorKind {
     UndelimitedString,
+    UnexpectedCharacter,
+    InvalidDigit,
+    MissingDigits,
+    MissingExponentDigits,
+    MisplacedSeparator,
+    NumberTooLarge,
+    UnterminatedComment,
+}
+
+impl ScanErrorKind {
+    /// Stable error code, see `diagnostics::Diagnostic`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ScanErrorKind::UndelimitedString => "E0001",
+            ScanErrorKind::UnexpectedCharacter => "E0002",
+            ScanErrorKind::InvalidDigit => "E0003",
+            ScanErrorKind::MissingDigits => "E0004",
+            ScanErrorKind::MissingExponentDigits => "E0005",
+            ScanErrorKind::MisplacedSeparator => "E0006",
+            ScanErrorKind::NumberTooLarge => "E0007",
+            ScanErrorKind::UnterminatedComment => "E0008",
+        }
+    }
+}
+
+impl fmt::Display for ScanErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanErrorKind::UndelimitedString => write!(f, "Unterminated string."),
+            ScanErrorKind::UnexpectedCharacter => write!(f, "Unexpected character."),
+            ScanErrorKind::InvalidDigit => write!(f, "Invalid digit in number literal."),
+            ScanErrorKind::MissingDigits => write!(f, "Expect digits after number prefix."),
+            ScanErrorKind::MissingExponentDigits => write!(f, "Expect digits in exponent."),
+            ScanErrorKind::MisplacedSeparator => write!(f, "Digit separator '_' must be between digits."),
+            ScanErrorKind::NumberTooLarge => write!(f, "Number literal is too large."),
+            ScanErrorKind::UnterminatedComment => write!(f, "Unterminated block comment."),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
-enum TokenType {
+pub(crate) enum TokenType {
     LeftParen,
     RightParen,
     LeftBrace,
@@ -60,37 +106,79 @@ enum TokenType {
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Token<'src> {
-    ttype: TokenType,
-    span: &'src str,
+    pub(crate) ttype: TokenType,
+    pub(crate) span: &'src str,
     line: usize,
+    column: usize,
+    offset: usize,
 }
 
-struct Source<'src> {
+impl Token<'_> {
+    pub(crate) fn source_span(&self) -> SourceSpan {
+        SourceSpan {
+            line: self.line,
+            column: self.column,
+            offset: self.offset,
+            len: self.span.len(),
+        }
+    }
+
+    /// The part of this token at the byte range `range`, e.g. an escape inside a string.
+    pub(crate) fn slice(&self, range: std::ops::Range<usize>) -> Self {
+        let before = &self.span[..range.start];
+        let (line, column) = match before.rsplit_once('\n') {
+            Some((skipped, last_line)) => (
+                self.line + skipped.matches('\n').count() + 1,
+                last_line.chars().count() + 1,
+            ),
+            None => (self.line, self.column + before.chars().count()),
+        };
+
+        Token {
+            ttype: self.ttype,
+            span: &self.span[range.clone()],
+            line,
+            column,
+            offset: self.offset + range.start,
+        }
+    }
+}
+
+/// Line and column (in characters) of a character, both 1-based.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Position {
     line: usize,
+    column: usize,
+}
+
+struct Source<'src> {
+    position: Position,
     iter: CharIndices<'src>,
 }
 
 impl<'src> Source<'src> {
     fn new(str: &'src str) -> Self {
         Source {
-            line: 1,
+            position: Position { line: 1, column: 1 },
             iter: str.char_indices(),
         }
     }
 }
 
 impl Iterator for Source<'_> {
-    type Item = (usize, usize, char);
+    type Item = (Position, usize, char);
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.iter.next() {
             Some((p, ch)) => {
-                let old_line = self.line;
+                let old_position = self.position;
                 if ch == '\n' {
-                    self.line += 1;
+                    self.position = Position { line: old_position.line + 1, column: 1 };
+                } else {
+                    self.position.column += 1;
                 }
 
-                Some((old_line, p, ch))
+                Some((old_position, p, ch))
             },
             None => None,
         }
@@ -110,19 +198,25 @@ impl<'src> Scanner<'src> {
         }
     }
 
-    fn make_token(&self, ttype: TokenType, line: usize, start: usize, end: usize) -> Token<'src> {
+    /// `start..end` is the byte range of the token in the source.
+    fn make_token(&self, ttype: TokenType, position: Position, start: usize, end: usize) -> Token<'src> {
         Token {
             ttype,
-            span: &self.source[start..=end],
-            line,
+            span: &self.source[start..end],
+            line: position.line,
+            column: position.column,
+            offset: start,
         }
     }
 
+    /// Consumes the next character if it matches, returning the offset just past it.
     fn consume_if<P>(&mut self, p: P) -> Option<usize>
     where
         P: Fn(char) -> bool,
     {
-        self.iter.next_if(|&(_, _, c)| p(c)).map(|(_l, p, _c)| p)
+        self.iter
+            .next_if(|&(_, _, c)| p(c))
+            .map(|(_l, p, c)| p + c.len_utf8())
     }
 
     fn consume_if_eq(&mut self, expected: char) -> Option<usize> {
@@ -133,58 +227,74 @@ impl<'src> Scanner<'src> {
     where
         P: Fn(char) -> bool + Copy,
     {
-        self.consume_if(p).map(|pos| {
-            let mut last = pos;
-            while let Some(pos) = self.consume_if(p) {
-                last = pos
+        self.consume_if(p).map(|end| {
+            let mut last = end;
+            while let Some(end) = self.consume_if(p) {
+                last = end
             }
             last
         })
     }
 
+    /// Scans to the closing quote, which a backslash escapes.
+    fn scan_string(&mut self) -> std::result::Result<usize, usize> {
+        while let Some((_, p, c)) = self.iter.next() {
+            match c {
+                '"' => return Ok(p + 1),
+                '\\' => {
+                    self.iter.next();
+                }
+                _ => {}
+            }
+        }
+
+        Err(self.source.len())
+    }
+
     fn consume_until_eq(&mut self, limit: char) -> std::result::Result<usize, usize> {
         for (_line, p, c) in self.iter.by_ref() {
             if c == limit {
-                return Ok(p);
+                return Ok(p + c.len_utf8());
             }
         }
 
         Err(self.source.len())
     }
 
-    fn scan_string(&mut self) -> std::result::Result<usize, usize> {
-        self.consume_until_eq('"')
-    }
-
-    fn scan_number(&mut self, start: usize) -> usize {
-        let mut end = start;
-        end = self
-            .consume_while(|c| c.is_ascii_alphanumeric())
-            .unwrap_or(end);
-
-        // todo: make robust to non-ascii chars
-
-        if let Some(pos) = self.consume_if_eq('.') {
-            end = pos;
+    /// Scans a number whose first digit ends at `end`, see `number_value` for the syntax.
+    ///
+    /// Letters directly following the digits are made part of the token, so
+    /// that `12ab` or `1é` is one invalid number rather than a number
+    /// followed by an identifier. A `.` only belongs to the number if a
+    /// digit follows it.
+    fn scan_number(&mut self, start: usize, mut end: usize) -> std::result::Result<usize, (usize, ScanErrorKind)> {
+        end = self.consume_while(is_identifier_continue).unwrap_or(end);
+
+        if radix_prefix(&self.source[start..end]).is_none() {
+            let rest = &self.source[end..];
+            if rest.starts_with('.') && rest[1..].starts_with(|c: char| c.is_ascii_digit()) {
+                end = self.consume_if_eq('.').unwrap_or(end);
+                end = self.consume_while(is_identifier_continue).unwrap_or(end);
+            }
 
-            end = self
-                .consume_while(|c| c.is_ascii_alphanumeric())
-                .unwrap_or(end);
+            if self.source[start..end].ends_with(['e', 'E']) {
+                if let Some(pos) = self.consume_if(|c| c == '+' || c == '-') {
+                    end = pos;
+                    end = self.consume_while(is_identifier_continue).unwrap_or(end);
+                }
+            }
         }
 
-        end
+        match number_value(&self.source[start..end]) {
+            Ok(_) => Ok(end),
+            Err(kind) => Err((end, kind)),
+        }
     }
 
-    fn scan_identifier(&mut self, line: usize, start: usize) -> Token<'src> {
-        let mut end = start;
-
-        end = self
-            .consume_while(|c| c.is_ascii_alphanumeric())
-            .unwrap_or(end);
+    fn scan_identifier(&mut self, position: Position, start: usize, end: usize) -> Token<'src> {
+        let end = self.consume_while(is_identifier_continue).unwrap_or(end);
 
-        let slice = &self.source[start..=end];
-
-        let ttype = match slice {
+        let ttype = match &self.source[start..end] {
             "and" => TokenType::And,
             "class" => TokenType::Class,
             "else" => TokenType::Else,
@@ -204,12 +314,168 @@ impl<'src> Scanner<'src> {
             _ => TokenType::Identifier,
         };
 
-        Token { ttype, span: slice, line }
+        self.make_token(ttype, position, start, end)
     }
 
     fn scan_comment(&mut self) {
         let _ = self.consume_until_eq('\n');
     }
+
+    /// Skips a `/* */` comment whose opening `/*` was consumed. Block comments nest.
+    fn scan_block_comment(&mut self) -> std::result::Result<(), ()> {
+        let mut depth = 1;
+
+        while let Some((_, _, c)) = self.iter.next() {
+            match c {
+                '/' if self.consume_if_eq('*').is_some() => depth += 1,
+                '*' if self.consume_if_eq('/').is_some() => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Err(())
+    }
+}
+
+/// The value of a number literal.
+///
+/// Numbers are decimal with an optional fraction and exponent (`1.5e-9`),
+/// hexadecimal (`0xFF`) or binary (`0b1010`). Digits may be grouped with `_`
+/// between them (`1_000_000`).
+pub(crate) fn number_value(literal: &str) -> std::result::Result<f64, ScanErrorKind> {
+    if let Some((radix, digits)) = radix_prefix(literal) {
+        check_digits(digits, radix)?;
+
+        return u128::from_str_radix(&digits.replace('_', ""), radix)
+            .map(|value| value as f64)
+            .map_err(|_| ScanErrorKind::NumberTooLarge);
+    }
+
+    let (mantissa, exponent) = match literal.find(['e', 'E']) {
+        Some(index) => (&literal[..index], Some(&literal[index + 1..])),
+        None => (literal, None),
+    };
+
+    let (integer, fraction) = match mantissa.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (mantissa, None),
+    };
+
+    check_digits(integer, 10)?;
+    if let Some(fraction) = fraction {
+        check_digits(fraction, 10)?;
+    }
+    if let Some(exponent) = exponent {
+        let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        check_digits(digits, 10).map_err(|kind| match kind {
+            ScanErrorKind::MissingDigits => ScanErrorKind::MissingExponentDigits,
+            kind => kind,
+        })?;
+    }
+
+    match literal.replace('_', "").parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(ScanErrorKind::NumberTooLarge),
+    }
+}
+
+/// The radix and digits of a hexadecimal or binary literal.
+fn radix_prefix(literal: &str) -> Option<(u32, &str)> {
+    [("0x", 16), ("0X", 16), ("0b", 2), ("0B", 2)]
+        .into_iter()
+        .find_map(|(prefix, radix)| literal.strip_prefix(prefix).map(|digits| (radix, digits)))
+}
+
+/// Checks that `digits` is a non-empty run of digits with `_` only between them.
+fn check_digits(digits: &str, radix: u32) -> std::result::Result<(), ScanErrorKind> {
+    if digits.is_empty() {
+        return Err(ScanErrorKind::MissingDigits);
+    }
+
+    for c in digits.chars() {
+        if c != '_' && !c.is_digit(radix) {
+            return Err(ScanErrorKind::InvalidDigit);
+        }
+    }
+
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return Err(ScanErrorKind::MisplacedSeparator);
+    }
+
+    Ok(())
+}
+
+/// Decodes the escape sequences in the contents of a string literal.
+///
+/// On error, returns the byte range of the offending escape and what is wrong with it.
+pub(crate) fn unescape(literal: &str) -> std::result::Result<Cow<'_, str>, (std::ops::Range<usize>, ParseErrorKind)> {
+    if !literal.contains('\\') {
+        return Ok(Cow::Borrowed(literal));
+    }
+
+    let mut decoded = String::with_capacity(literal.len());
+    let mut chars = literal.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+
+        let Some((p, escaped)) = chars.next() else {
+            return Err((start..literal.len(), ParseErrorKind::InvalidEscape));
+        };
+        let mut end = p + escaped.len_utf8();
+
+        match escaped {
+            'n' => decoded.push('\n'),
+            't' => decoded.push('\t'),
+            '\\' => decoded.push('\\'),
+            '"' => decoded.push('"'),
+            'u' => {
+                let invalid = |end| Err((start..end, ParseErrorKind::InvalidUnicodeEscape));
+
+                if chars.next_if(|&(_, c)| c == '{').is_none() {
+                    return invalid(end);
+                }
+                end += 1;
+
+                let digits_start = end;
+                while chars.next_if(|&(_, c)| c.is_ascii_hexdigit()).is_some() {
+                    end += 1;
+                }
+                let digits = &literal[digits_start..end];
+
+                if chars.next_if(|&(_, c)| c == '}').is_none() {
+                    return invalid(end);
+                }
+                end += 1;
+
+                match u32::from_str_radix(digits, 16).ok().and_then(char::from_u32) {
+                    Some(c) if digits.len() <= 6 => decoded.push(c),
+                    _ => return invalid(end),
+                }
+            }
+            _ => return Err((start..end, ParseErrorKind::InvalidEscape)),
+        }
+    }
+
+    Ok(Cow::Owned(decoded))
+}
+
+/// Identifiers follow Unicode's XID rules (UAX #31), with `_` also allowed
+/// as the first character. They are compared as written, without normalization.
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || c.is_xid_start()
+}
+
+fn is_identifier_continue(c: char) -> bool {
+    c.is_xid_continue()
 }
 
 impl<'src> Iterator for Scanner<'src> {
@@ -218,14 +484,16 @@ impl<'src> Iterator for Scanner<'src> {
     fn next(&mut self) -> Option<Self::Item> {
         self.consume_while(|ch| ch.is_ascii_whitespace());
 
-        if let Some((start_line, start_pos, start_ch)) = self.iter.next() {
+        if let Some((start, start_pos, start_ch)) = self.iter.next() {
+            let start_end = start_pos + start_ch.len_utf8();
+
             let make_simple_token =
-                |s: &Self, ttype: TokenType| Some(s.make_token(ttype, start_line, start_pos, start_pos));
+                |s: &Self, ttype: TokenType| Some(s.make_token(ttype, start, start_pos, start_end));
 
             let handle_eq_suffix = |s: &mut Self, if_present: TokenType, if_absent: TokenType| {
                 Some(match s.consume_if_eq('=') {
-                    Some(end) => s.make_token(if_present, start_line, start_pos, end),
-                    None => s.make_token(if_absent, start_line, start_pos, start_pos),
+                    Some(end) => s.make_token(if_present, start, start_pos, end),
+                    None => s.make_token(if_absent, start, start_pos, start_end),
                 })
             };
 
@@ -239,13 +507,19 @@ impl<'src> Iterator for Scanner<'src> {
                 '-' => make_simple_token(self, TokenType::Minus),
                 '+' => make_simple_token(self, TokenType::Plus),
                 ';' => make_simple_token(self, TokenType::Semicolon),
-                '/' => match self.consume_if_eq('/') {
-                    Some(_) => {
+                '/' => {
+                    if self.consume_if_eq('/').is_some() {
                         self.scan_comment();
                         self.next()
+                    } else if let Some(end) = self.consume_if_eq('*') {
+                        match self.scan_block_comment() {
+                            Ok(()) => self.next(),
+                            Err(()) => Some(self.make_token(TokenType::Error(ScanErrorKind::UnterminatedComment), start, start_pos, end)),
+                        }
+                    } else {
+                        make_simple_token(self, TokenType::Slash)
                     }
-                    None => make_simple_token(self, TokenType::Slash),
-                },
+                }
                 '*' => make_simple_token(self, TokenType::Star),
                 '!' => handle_eq_suffix(self, TokenType::BangEqual, TokenType::Bang),
                 '=' => handle_eq_suffix(self, TokenType::EqualEqual, TokenType::Equal),
@@ -253,17 +527,19 @@ impl<'src> Iterator for Scanner<'src> {
                 '<' => handle_eq_suffix(self, TokenType::LessEqual, TokenType::Less),
                 _ => {
                     let token = if start_ch.is_ascii_digit() {
-                        let end = self.scan_number(start_pos);
-                        self.make_token(TokenType::Number, start_line, start_pos, end)
-                    } else if start_ch.is_ascii_alphabetic() {
-                        self.scan_identifier(start_line, start_pos)
+                        match self.scan_number(start_pos, start_end) {
+                            Ok(end) => self.make_token(TokenType::Number, start, start_pos, end),
+                            Err((end, kind)) => self.make_token(TokenType::Error(kind), start, start_pos, end),
+                        }
+                    } else if is_identifier_start(start_ch) {
+                        self.scan_identifier(start, start_pos, start_end)
                     } else if start_ch == '"' {
                         match self.scan_string() {
-                            Ok(end) => self.make_token(TokenType::String, start_line, start_pos, end),
-                            Err(end) => self.make_token(TokenType::Error(ScanErrorKind::UndelimitedString), start_line, start_pos, end - 1),
+                            Ok(end) => self.make_token(TokenType::String, start, start_pos, end),
+                            Err(end) => self.make_token(TokenType::Error(ScanErrorKind::UndelimitedString), start, start_pos, end),
                         }
                     } else {
-                        panic!("Invalid character");
+                        self.make_token(TokenType::Error(ScanErrorKind::UnexpectedCharacter), start, start_pos, start_end)
                     };
 
                     Some(token)
@@ -275,93 +551,75 @@ impl<'src> Iterator for Scanner<'src> {
     }
 }
 
-struct Compiler {
-    locals: Vec<(String, usize, bool)>,
-    scope_depth: usize,
+/// Peekable token stream for the parser that diverts scan errors.
+///
+/// Error tokens are never handed to the parser; they are set aside until the
+/// parser collects them with `take_scan_errors`.
+pub(crate) struct TokenStream<'src> {
+    scanner: Scanner<'src>,
+    peeked: Option<Option<Token<'src>>>,
+    scan_errors: Vec<Token<'src>>,
 }
 
-impl Default for Compiler {
-    fn default() -> Self {
-        Compiler {
-            locals: Vec::new(),
-            scope_depth: 0,
+impl<'src> TokenStream<'src> {
+    pub(crate) fn new(scanner: Scanner<'src>) -> Self {
+        TokenStream {
+            scanner,
+            peeked: None,
+            scan_errors: Vec::new(),
         }
     }
-}
-
-enum LocalsError {
-    TooMany,
-    DuplicateInScope,
-}
 
-impl Compiler {
-    fn enter_scope(&mut self) {
-        self.scope_depth += 1;
-    }
-
-    fn exit_scope(&mut self) -> usize {
-        let mut pop_count = 0;
-        while let Some(local) = self.locals.last() {
-            if local.1 < self.scope_depth {
-                break;
+    fn advance(&mut self) -> Option<Token<'src>> {
+        for token in self.scanner.by_ref() {
+            match token.ttype {
+                TokenType::Error(_) => self.scan_errors.push(token),
+                _ => return Some(token),
             }
-            self.locals.pop();
-            pop_count += 1;
         }
-        self.scope_depth -= 1;
-        return pop_count;
-    }
 
-    fn in_global_scope(&self) -> bool {
-        self.scope_depth <= 0
+        None
     }
 
-    fn declare_local(&mut self, name: &str) -> std::result::Result<(), LocalsError> {
-        if self.locals.len() > u8::MAX as usize {
-            Err(LocalsError::TooMany)
-        } else {
-            for idx in (0..self.locals.len()).rev() {
-                if self.locals[idx].1 < self.scope_depth {
-                    break
-                }
+    pub(crate) fn peek(&mut self) -> Option<&Token<'src>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.advance());
+        }
 
-                if self.locals[idx].0 == name {
-                    return Err(LocalsError::DuplicateInScope)
-                }
-            }
+        self.peeked.as_ref().and_then(Option::as_ref)
+    }
 
-            self.locals.push((name.to_string(), self.scope_depth, false));
-            Ok(())
+    pub(crate) fn next_if(&mut self, p: impl FnOnce(&Token<'src>) -> bool) -> Option<Token<'src>> {
+        match self.peek() {
+            Some(token) if p(token) => self.next(),
+            _ => None,
         }
     }
 
-    fn mark_last_initialized(&mut self) {
-       self.locals.last_mut().unwrap().2 = true;
+    pub(crate) fn take_scan_errors(&mut self) -> Vec<Token<'src>> {
+        std::mem::take(&mut self.scan_errors)
     }
+}
 
-    // Err ( false ) -> not declared
-    // Err ( true ) -> declared but not initialized
-    fn resolve_local(&self, target: &str) -> std::result::Result<u8, bool> {
-        for idx in (0..self.locals.len()).rev() {
-            if self.locals[idx].0 == target {
-                return if !self.locals[idx].2 {
-                    Err(true)
-                } else {
-                    Ok(idx as u8)
-                }
-            }
-        }
+impl<'src> Iterator for TokenStream<'src> {
+    type Item = Token<'src>;
 
-        Err(false)
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.peeked.take() {
+            Some(peeked) => peeked,
+            None => self.advance(),
+        }
     }
 }
 
+/// How deeply expressions and blocks may nest before the parser gives up,
+/// so that pathological input cannot overflow the stack.
+pub(crate) const MAX_NESTING: usize = 256;
+
 struct Parser<'src> {
-    scanner: Peekable<Scanner<'src>>,
-    errors: Vec<ParseError<'src>>,
-    intern_table: HashMap<&'src str, u8>,
-    end_line: usize,
-    compiler: Compiler,
+    cursor: Cursor<'src>,
+    strings: Strings<'src>,
+    scopes: Scopes<'src>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -378,13 +636,52 @@ pub enum ParseErrorKind {
     RightBraceAfterBlock,
     TooManyLocals,
     DuplicateLocalInScope,
-    LocalInOwnInitializer
+    LocalInOwnInitializer,
+    TooManyArguments,
+    NoRightParenAfterArguments,
+    InvalidEscape,
+    InvalidUnicodeEscape,
+    NoRightParenAfterExpression,
+    TooManyConstants,
+    TooDeeplyNested,
+    TooManyRegisters,
+    TooManyGlobals,
+}
+
+impl ParseErrorKind {
+    /// Stable error code, see `diagnostics::Diagnostic`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseErrorKind::ScanError(err) => err.code(),
+            ParseErrorKind::InvalidNumber => "E0100",
+            ParseErrorKind::IncompleteExpression => "E0101",
+            ParseErrorKind::NoSemicolonAfterValue => "E0102",
+            ParseErrorKind::NoSemicolonAfterExpression => "E0103",
+            ParseErrorKind::NoVariableName => "E0104",
+            ParseErrorKind::NoSemicolonAfterVarDecl => "E0105",
+            ParseErrorKind::InvalidAssignmentTarget => "E0106",
+            ParseErrorKind::InvalidVariableName => "E0107",
+            ParseErrorKind::RightBraceAfterBlock => "E0108",
+            ParseErrorKind::TooManyLocals => "E0109",
+            ParseErrorKind::DuplicateLocalInScope => "E0110",
+            ParseErrorKind::LocalInOwnInitializer => "E0111",
+            ParseErrorKind::TooManyArguments => "E0112",
+            ParseErrorKind::NoRightParenAfterArguments => "E0113",
+            ParseErrorKind::InvalidEscape => "E0114",
+            ParseErrorKind::InvalidUnicodeEscape => "E0115",
+            ParseErrorKind::NoRightParenAfterExpression => "E0116",
+            ParseErrorKind::TooManyConstants => "E0117",
+            ParseErrorKind::TooDeeplyNested => "E0118",
+            ParseErrorKind::TooManyRegisters => "E0119",
+            ParseErrorKind::TooManyGlobals => "E0120",
+        }
+    }
 }
 
 impl fmt::Display for ParseErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ParseErrorKind::InvalidNumber => todo!(),
+            ParseErrorKind::InvalidNumber => write!(f, "Invalid number literal."),
             ParseErrorKind::IncompleteExpression => write!(f, "Expect expression."),
             ParseErrorKind::NoSemicolonAfterValue => write!(f, "Expect ';' after value."),
             ParseErrorKind::NoSemicolonAfterExpression => write!(f, "Expect ';' after expression."),
@@ -392,12 +689,20 @@ impl fmt::Display for ParseErrorKind {
             ParseErrorKind::NoSemicolonAfterVarDecl => write!(f, "Expect ';' after variable declaration."),
             ParseErrorKind::InvalidAssignmentTarget => write!(f, "Invalid assignment target."),
             ParseErrorKind::InvalidVariableName => write!(f, "Expect variable name."),
-            ParseErrorKind::ScanError(ScanErrorKind::UndelimitedString) =>
-                write!(f, "Unterminated string."),
+            ParseErrorKind::ScanError(err) => write!(f, "{}", err),
             ParseErrorKind::RightBraceAfterBlock => write!(f, "Expect '}}' after block."),
             ParseErrorKind::TooManyLocals => write!(f, "Too many local variables in function."),
             ParseErrorKind::DuplicateLocalInScope => write!(f, "Already a variable with this name in this scope."),
             ParseErrorKind::LocalInOwnInitializer => write!(f, "Can't read local variable in its own initializer."),
+            ParseErrorKind::TooManyArguments => write!(f, "Can't have more than 255 arguments."),
+            ParseErrorKind::NoRightParenAfterArguments => write!(f, "Expect ')' after arguments."),
+            ParseErrorKind::InvalidEscape => write!(f, "Invalid escape sequence."),
+            ParseErrorKind::InvalidUnicodeEscape => write!(f, "Invalid unicode escape sequence."),
+            ParseErrorKind::NoRightParenAfterExpression => write!(f, "Expect ')' after expression."),
+            ParseErrorKind::TooManyConstants => write!(f, "Too many constants in one chunk."),
+            ParseErrorKind::TooDeeplyNested => write!(f, "Too much nesting."),
+            ParseErrorKind::TooManyRegisters => write!(f, "Too many registers in one chunk."),
+            ParseErrorKind::TooManyGlobals => write!(f, "Too many global variables in one chunk."),
         }
     }
 }
@@ -405,8 +710,53 @@ impl fmt::Display for ParseErrorKind {
 #[derive(Debug, PartialEq)]
 pub struct ParseError<'src> {
     location: Option<Token<'src>>,
-    line: usize,
+    span: SourceSpan,
     kind: ParseErrorKind,
+    /// Other places in the source that help explain the error.
+    labels: Vec<(SourceSpan, &'static str)>,
+}
+
+impl<'src> ParseError<'src> {
+    pub(crate) fn at(location: Token<'src>, kind: ParseErrorKind) -> Self {
+        let span = location.source_span();
+        ParseError {
+            location: Some(location),
+            span,
+            kind,
+            labels: Vec::new(),
+        }
+    }
+
+    /// An error at `end`, the end of the source.
+    pub(crate) fn at_end(end: SourceSpan, kind: ParseErrorKind) -> Self {
+        ParseError {
+            location: None,
+            span: end,
+            kind,
+            labels: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_label(mut self, span: SourceSpan, message: &'static str) -> Self {
+        self.labels.push((span, message));
+        self
+    }
+
+    /// Whether the error is at a keyword the compiler does not implement yet,
+    /// such as `fun`, `class` or `while`.
+    pub fn is_unsupported(&self) -> bool {
+        use TokenType::*;
+        self.location.as_ref().is_some_and(|location| {
+            matches!(location.ttype, And | Class | Else | For | Fun | If | Or | Return | Super | This | While)
+        })
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        self.labels.iter().fold(
+            Diagnostic::new(Stage::Compile, self.kind.code(), self.kind.to_string(), self.span),
+            |diagnostic, &(span, message)| diagnostic.with_label(span, message),
+        )
+    }
 }
 
 impl<'src> fmt::Display for ParseError<'src> {
@@ -414,12 +764,12 @@ impl<'src> fmt::Display for ParseError<'src> {
         match &self.location {
             Some(location) => {
                 match location.ttype {
-                    TokenType::Error(_) => write!(f, "[line {}] Error: {}", self.line, self.kind),
-                    _ => write!(f, "[line {}] Error at '{}': {}", self.line, location.span, self.kind),
+                    TokenType::Error(_) => write!(f, "[line {}] Error: {}", self.span.line, self.kind),
+                    _ => write!(f, "[line {}] Error at '{}': {}", self.span.line, location.span, self.kind),
                 }
             }
             None => {
-                write!(f, "[line {}] Error at end: {}", self.line, self.kind)
+                write!(f, "[line {}] Error at end: {}", self.span.line, self.kind)
             },
         }
     }
@@ -432,35 +782,20 @@ enum Associativity {
 }
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
-enum Precedence {
+pub(crate) enum Precedence {
     None,
     Assignment,
-    Or,
-    And,
     Equality,
     Comparison,
     Term,
     Factor,
     Unary,
     Call,
-    Primary,
 }
 
-type Result<'src, T> = std::result::Result<T, ParseError<'src>>;
-
-impl<'src> Parser<'src> {
-    fn new(sc: Scanner<'src>) -> Self {
-        let line_count = sc.source.chars().filter(|c| *c == '\n').count() + 1;
-        Parser {
-            scanner: sc.into_iter().peekable(),
-            errors: Vec::new(),
-            intern_table: HashMap::new(),
-            end_line: line_count,
-            compiler: Default::default(),
-        }
-    }
-
-    fn precedence(ttype: TokenType) -> Option<Precedence> {
+impl Precedence {
+    /// Precedence of the infix operator `ttype`, if it is one.
+    pub(crate) fn of(ttype: TokenType) -> Option<Precedence> {
         use TokenType::*;
         match ttype {
             Plus | Minus => Some(Precedence::Term),
@@ -468,167 +803,360 @@ impl<'src> Parser<'src> {
             EqualEqual | BangEqual => Some(Precedence::Equality),
             Greater | GreaterEqual | Less | LessEqual => Some(Precedence::Comparison),
             Equal => Some(Precedence::Assignment),
+            LeftParen => Some(Precedence::Call),
             _ => None,
         }
     }
 
-    fn associativity(prec: Precedence) -> Associativity {
+    fn associativity(self) -> Associativity {
         use Precedence::*;
-        match prec {
-            Term | Factor | Equality | Comparison => Associativity::Left,
+        match self {
+            Term | Factor | Equality | Comparison | Call => Associativity::Left,
             None => Associativity::Left,
             Unary => Associativity::Right,
             _ => Associativity::NonAssoc,
         }
     }
 
-    fn error_end(&self, kind: ParseErrorKind) -> ParseError<'src> {
-        ParseError {
-            location: None,
-            line: self.end_line,
-            kind
+    /// Whether an operator of this precedence binds to the operand parsed at `min_prec`.
+    pub(crate) fn binds(self, min_prec: Precedence) -> bool {
+        match self.cmp(&min_prec) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => match min_prec.associativity() {
+                Associativity::Left => false,
+                Associativity::Right => true,
+                // Taken so that the caller reports it, e.g. the second '=' in `a = 1 = 2`.
+                Associativity::NonAssoc => true,
+            },
         }
     }
+}
 
-    fn error_at(&self, location: Token<'src>, kind: ParseErrorKind) -> ParseError<'src> {
-        let line = location.line;
-        ParseError {
-            location: Some(location),
-            line,
-            kind
+type Result<'src, T> = std::result::Result<T, ParseError<'src>>;
+
+/// Where "Error at end" errors point: just past the last character.
+pub(crate) fn end_span(source: &str) -> SourceSpan {
+    let line_count = source.chars().filter(|c| *c == '\n').count() + 1;
+    let last_line = source.rsplit('\n').next().unwrap_or_default();
+    SourceSpan {
+        line: line_count,
+        column: last_line.chars().count() + 1,
+        offset: source.len(),
+        len: 0,
+    }
+}
+
+/// The token stream of a parser, with the bookkeeping shared by `Parser` and
+/// `ast::Parser`: the errors found so far, how deeply the construct being
+/// parsed is nested, and panic-mode recovery.
+pub(crate) struct Cursor<'src> {
+    pub(crate) tokens: TokenStream<'src>,
+    pub(crate) errors: Vec<ParseError<'src>>,
+    end_span: SourceSpan,
+    depth: usize,
+}
+
+impl<'src> Cursor<'src> {
+    pub(crate) fn new(scanner: Scanner<'src>) -> Self {
+        Cursor {
+            end_span: end_span(scanner.source),
+            tokens: TokenStream::new(scanner),
+            errors: Vec::new(),
+            depth: 0,
         }
     }
 
-    fn error_at_or_end(&self, location: Option<Token<'src>>, kind: ParseErrorKind) -> ParseError<'src> {
-        match location {
-            Some(location) => self.error_at(location, kind),
+    pub(crate) fn error_end(&self, kind: ParseErrorKind) -> ParseError<'src> {
+        ParseError::at_end(self.end_span, kind)
+    }
+
+    /// An error at the next token, or at the end if there is none.
+    pub(crate) fn error_at_next(&mut self, kind: ParseErrorKind) -> ParseError<'src> {
+        match self.tokens.peek().cloned() {
+            Some(location) => ParseError::at(location, kind),
             None => self.error_end(kind),
         }
     }
 
-    fn add_string(&mut self, chunk: &mut Chunk, string: &'src str) -> u8 {
-        match self.intern_table.entry(string) {
-            hash_map::Entry::Occupied(entry) => {
-                entry.get().clone()
-            },
-            hash_map::Entry::Vacant(entry) => {
-                let handle = GC::new_string(string);
-                chunk.add_constant_value(Value::from(handle.get_object()));
-                chunk.allocations.push_front(handle);
-                let offset = chunk.constants.len() as u8 - 1;
-                entry.insert(offset).clone()
-            },
+    pub(crate) fn must_consume(&mut self, expected: TokenType, error_kind: ParseErrorKind) -> Result<'src, Token<'src>> {
+        match self.tokens.next_if(|token| token.ttype == expected) {
+            Some(token) => Ok(token),
+            None => Err(self.error_at_next(error_kind)),
+        }
+    }
+
+    /// The next operator if it binds tighter than `min_prec`.
+    pub(crate) fn next_operator(&mut self, min_prec: Precedence) -> Option<(Token<'src>, Precedence)> {
+        let op_prec = self.tokens.peek().and_then(|token| Precedence::of(token.ttype))?;
+
+        if op_prec.binds(min_prec) {
+            self.tokens.next().map(|op| (op, op_prec))
+        } else {
+            None
+        }
+    }
+
+    /// The name of the variable a `var` declaration declares.
+    pub(crate) fn variable_name(&mut self) -> Result<'src, Token<'src>> {
+        let name = self.must_consume(TokenType::Identifier, ParseErrorKind::NoVariableName)?;
+
+        if name.span == "nil" {
+            Err(ParseError::at(name, ParseErrorKind::InvalidVariableName))
+        } else {
+            Ok(name)
+        }
+    }
+
+    /// Finishes a declaration. Like clox's panic mode, a declaration containing
+    /// a scan error reports only the scan error, not the parse errors that
+    /// follow from skipping it, and an error skips to the next statement.
+    pub(crate) fn end_declaration<T>(&mut self, result: Result<'src, T>) -> Option<T> {
+        let had_scan_errors = self.report_scan_errors();
+
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                if !had_scan_errors {
+                    self.errors.push(err);
+                }
+                self.synchronize();
+                None
+            }
         }
     }
 
+    fn synchronize(&mut self) {
+        use TokenType::*;
+
+        while let Some(peek) = self.tokens.peek() {
+            if peek.ttype == TokenType::Semicolon {
+                self.tokens.next();
+                return;
+            }
+
+            if [Class, Fun, Var, For, If, While, Print, Return].contains(&peek.ttype) {
+                return;
+            }
+
+            self.tokens.next();
+        }
+    }
+
+    /// Reports the scan errors since the last call, and whether there were any.
+    pub(crate) fn report_scan_errors(&mut self) -> bool {
+        let scan_errors = self.tokens.take_scan_errors();
+        let had_scan_errors = !scan_errors.is_empty();
+
+        for token in scan_errors {
+            if let TokenType::Error(err) = token.ttype {
+                self.errors.push(ParseError::at(token, ParseErrorKind::ScanError(err)));
+            }
+        }
+
+        had_scan_errors
+    }
+}
+
+/// Grammar rules shared by `Parser` and `ast::Parser`, which differ in what
+/// they do with the expressions they parse.
+pub(crate) trait TokenParser<'src>: Sized {
+    fn cursor(&mut self) -> &mut Cursor<'src>;
+
+    /// Runs `parse` one nesting level deeper, failing once `MAX_NESTING` is reached.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<'src, T>) -> Result<'src, T> {
+        let cursor = self.cursor();
+        if cursor.depth >= MAX_NESTING {
+            return Err(cursor.error_at_next(ParseErrorKind::TooDeeplyNested));
+        }
+
+        cursor.depth += 1;
+        let result = parse(self);
+        self.cursor().depth -= 1;
+        result
+    }
+
+    /// Parses the arguments of a call, up to and including the ')', with
+    /// `argument` and returns how many there are.
+    fn arguments(&mut self, mut argument: impl FnMut(&mut Self) -> Result<'src, ()>) -> Result<'src, u8> {
+        let mut arg_count: u8 = 0;
+
+        if self.cursor().tokens.next_if(|token| token.ttype == TokenType::RightParen).is_some() {
+            return Ok(arg_count);
+        }
+
+        loop {
+            argument(self)?;
+
+            arg_count = match arg_count.checked_add(1) {
+                Some(count) => count,
+                None => return Err(self.cursor().error_at_next(ParseErrorKind::TooManyArguments)),
+            };
+
+            if self.cursor().tokens.next_if(|token| token.ttype == TokenType::Comma).is_none() {
+                break;
+            }
+        }
+
+        self.cursor().must_consume(TokenType::RightParen, ParseErrorKind::NoRightParenAfterArguments)?;
+
+        Ok(arg_count)
+    }
+}
+
+/// Offset of the next constant in `constants`, or an error at `location` if there is no room left.
+pub(crate) fn next_constant<'src>(constants: &[Value], location: &Token<'src>) -> Result<'src, u8> {
+    u8::try_from(constants.len()).map_err(|_| ParseError::at(location.clone(), ParseErrorKind::TooManyConstants))
+}
+
+/// Slot of the global variable `name` in `globals`, or an error at `name` if there is no room left.
+pub(crate) fn global_slot<'src>(globals: &mut Globals, name: &Token<'src>) -> Result<'src, u8> {
+    globals
+        .slot(name.span)
+        .ok_or_else(|| ParseError::at(name.clone(), ParseErrorKind::TooManyGlobals))
+}
+
+/// The string constants of the chunk being compiled, so that each string is stored once.
+#[derive(Default)]
+pub(crate) struct Strings<'src> {
+    offsets: HashMap<Cow<'src, str>, u8>,
+}
+
+impl<'src> Strings<'src> {
+    /// Offset of the constant holding `string`, which is added to `constants`
+    /// unless it is there already.
+    pub(crate) fn intern(
+        &mut self,
+        string: impl Into<Cow<'src, str>>,
+        constants: &mut Vec<Value>,
+        allocations: &mut LinkedList<GcHandle>,
+        location: &Token<'src>,
+    ) -> Result<'src, u8> {
+        let string = string.into();
+        if let Some(&offset) = self.offsets.get(&string) {
+            return Ok(offset);
+        }
+
+        let offset = next_constant(constants, location)?;
+        let handle = GC::new_string(&string);
+        constants.push(Value::from(handle.get_object()));
+        allocations.push_front(handle);
+        self.offsets.insert(string, offset);
+        Ok(offset)
+    }
+}
+
+impl<'src> TokenParser<'src> for Parser<'src> {
+    fn cursor(&mut self) -> &mut Cursor<'src> {
+        &mut self.cursor
+    }
+}
+
+impl<'src> Parser<'src> {
+    fn new(sc: Scanner<'src>) -> Self {
+        Parser {
+            cursor: Cursor::new(sc),
+            strings: Strings::default(),
+            scopes: Scopes::default(),
+        }
+    }
+
+    fn add_string(&mut self, chunk: &mut Chunk, string: impl Into<Cow<'src, str>>, location: &Token<'src>) -> Result<'src, u8> {
+        self.strings.intern(string, &mut chunk.constants, &mut chunk.allocations, location)
+    }
+
     fn _expression(&mut self, chunk: &mut Chunk, min_prec: Precedence) -> Result<'src, ()> {
-        match self.scanner.next() {
-            None => return Err(self.error_end(ParseErrorKind::IncompleteExpression)),
+        self.nested(|parser| parser.parse_precedence(chunk, min_prec))
+    }
+
+    fn parse_precedence(&mut self, chunk: &mut Chunk, min_prec: Precedence) -> Result<'src, ()> {
+        match self.cursor.tokens.next() {
+            None => return Err(self.cursor.error_end(ParseErrorKind::IncompleteExpression)),
             Some(token) => match token.ttype {
                 TokenType::Minus | TokenType::Bang => {
                     self._expression(chunk, Precedence::Unary)?;
-                    let op = match token.ttype {
-                        TokenType::Minus => Op::Negate,
-                        TokenType::Bang => Op::Not,
-                        _ => unreachable!(),
-                    };
-                    chunk.add_op(op, token.line);
+                    let op = if token.ttype == TokenType::Minus { Op::Negate } else { Op::Not };
+                    chunk.add_op(op, token.source_span());
                 }
                 TokenType::Number => {
-                    match token.span.parse::<f64>() {
-                        Ok(c) => Ok(chunk.add_constant(c.into(), token.line)),
-                        _ => Err(self.error_at(token, ParseErrorKind::InvalidNumber)),
-                    }?;
+                    let value = number_value(token.span)
+                        .map_err(|_| ParseError::at(token.clone(), ParseErrorKind::InvalidNumber))?;
+                    next_constant(&chunk.constants, &token)?;
+                    chunk.add_constant(value.into(), token.source_span());
                 }
                 TokenType::String => {
                     let without_quotes = &token.span[1..(token.span.len() - 1)];
-                    let offset = self.add_string(chunk, without_quotes);
+                    let string = unescape(without_quotes)
+                        .map_err(|(range, kind)| ParseError::at(token.slice(range.start + 1..range.end + 1), kind))?;
+                    let offset = self.add_string(chunk, string, &token)?;
                     chunk.add_op(
                         Op::Constant {
                             offset,
                         },
-                        token.line
+                        token.source_span()
                     );
                 }
                 TokenType::LeftParen => {
                     self._expression(chunk, Precedence::None)?;
-                    assert_eq!(self.scanner.next().unwrap().ttype, TokenType::RightParen)
+                    self.cursor.must_consume(TokenType::RightParen, ParseErrorKind::NoRightParenAfterExpression)?;
                 }
                 TokenType::Nil => {
-                    chunk.add_op(Op::Nil, token.line);
+                    chunk.add_op(Op::Nil, token.source_span());
                 }
                 TokenType::True => {
-                    chunk.add_op(Op::True, token.line);
+                    chunk.add_op(Op::True, token.source_span());
                 }
                 TokenType::False => {
-                    chunk.add_op(Op::False, token.line);
+                    chunk.add_op(Op::False, token.source_span());
                 }
                 TokenType::Identifier => {
-                    let (get_op, set_op) = match self.compiler.resolve_local(&token.span) {
-                        Ok(offset) => (Op::GetLocal { offset }, Op::SetLocal { offset }),
-                        Err(true) => {
-                            return Err(self.error_at(token, ParseErrorKind::LocalInOwnInitializer));
-                        },
-                        Err(false) => {
-                            let offset = self.add_string(chunk, token.span);
-                            (Op::GetGlobal { offset }, Op::SetGlobal { offset })
-
+                    let (get_op, set_op) = match self.scopes.resolve(&token)? {
+                        Binding::Local(offset) => (Op::GetLocal { offset }, Op::SetLocal { offset }),
+                        Binding::Global => {
+                            let slot = global_slot(&mut chunk.globals, &token)?;
+                            (Op::GetGlobal { slot }, Op::SetGlobal { slot })
                         }
                     };
 
-                    if let Some(eq_token) = self.scanner.next_if(|token| token.ttype == TokenType::Equal) {
+                    if let Some(eq_token) = self.cursor.tokens.next_if(|token| token.ttype == TokenType::Equal) {
                         if min_prec <= Precedence::Assignment {
                             self._expression(chunk, Precedence::Assignment)?;
-                            chunk.add_op(set_op, token.line);
+                            chunk.add_op(set_op, token.source_span());
                         } else {
-                            return Err(self.error_at(eq_token, ParseErrorKind::InvalidAssignmentTarget));
+                            return Err(ParseError::at(eq_token, ParseErrorKind::InvalidAssignmentTarget));
                         }
                     } else {
-                        chunk.add_op(get_op, token.line);
+                        chunk.add_op(get_op, token.source_span());
                     };
                 }
-                TokenType::Error(err) => {
-                    return Err(self.error_at(token, ParseErrorKind::ScanError(err)));
-                }
                 _ => {
-                    return Err(self.error_at(token, ParseErrorKind::IncompleteExpression));
+                    return Err(ParseError::at(token, ParseErrorKind::IncompleteExpression));
                 }
             },
         };
 
-        while let Some(op) = self.scanner.next_if(|token| {
-            if let Some(op_prec) = Self::precedence(token.ttype) {
-                if op_prec == min_prec {
-                    match Self::associativity(min_prec) {
-                        Associativity::Left => false,
-                        Associativity::Right => true,
-                        Associativity::NonAssoc => {
-                            panic!("NonAssoc operation found in associative position")
-                        }
-                    }
-                } else {
-                    op_prec > min_prec
-                }
-            } else {
-                false
+        while let Some((op, op_prec)) = self.cursor.next_operator(min_prec) {
+            if op.ttype == TokenType::LeftParen {
+                let arg_count = self.arguments(|parser| parser.expression(chunk))?;
+                chunk.add_op(Op::Call { arg_count }, op.source_span());
+                continue;
             }
-        }) {
+
             // Generates code for rhs
-            self._expression(chunk, Self::precedence(op.ttype).unwrap())?;
+            self._expression(chunk, op_prec)?;
 
             match op.ttype {
-                TokenType::Plus => chunk.add_op(Op::Add, op.line),
-                TokenType::Minus => chunk.add_op(Op::Subtract, op.line),
-                TokenType::Star => chunk.add_op(Op::Multiply, op.line),
-                TokenType::Slash => chunk.add_op(Op::Divide, op.line),
-                TokenType::EqualEqual => chunk.add_op(Op::Equal, op.line),
-                TokenType::Greater => chunk.add_op(Op::Greater, op.line),
-                TokenType::Less => chunk.add_op(Op::Less, op.line),
-                TokenType::BangEqual => chunk.add_op(Op::Equal, op.line).add_op(Op::Not, op.line),
-                TokenType::GreaterEqual => chunk.add_op(Op::Less, op.line).add_op(Op::Not, op.line),
-                TokenType::LessEqual => chunk.add_op(Op::Greater, op.line).add_op(Op::Not, op.line),
-                TokenType::Equal => {return Err(self.error_at(op, ParseErrorKind::InvalidAssignmentTarget))},
-                _ => unreachable!(),
+                TokenType::Plus => chunk.add_op(Op::Add, op.source_span()),
+                TokenType::Minus => chunk.add_op(Op::Subtract, op.source_span()),
+                TokenType::Star => chunk.add_op(Op::Multiply, op.source_span()),
+                TokenType::Slash => chunk.add_op(Op::Divide, op.source_span()),
+                TokenType::EqualEqual => chunk.add_op(Op::Equal, op.source_span()),
+                TokenType::Greater => chunk.add_op(Op::Greater, op.source_span()),
+                TokenType::Less => chunk.add_op(Op::Less, op.source_span()),
+                TokenType::BangEqual => chunk.add_op(Op::NotEqual, op.source_span()),
+                TokenType::GreaterEqual => chunk.add_op(Op::GreaterEqual, op.source_span()),
+                TokenType::LessEqual => chunk.add_op(Op::LessEqual, op.source_span()),
+                _ => return Err(ParseError::at(op, ParseErrorKind::InvalidAssignmentTarget)),
             };
         }
 
@@ -639,145 +1167,97 @@ impl<'src> Parser<'src> {
         self._expression(chunk, Precedence::None)
     }
 
-    fn must_consume(&mut self, expected: TokenType, error_kind: ParseErrorKind) -> Result<'src, Token<'src>> {
-        match self.scanner.peek().cloned() {
-            Some(token) if token.ttype == expected => Ok(self.scanner.next().unwrap()),
-            Some(token) => Err(self.error_at(token.clone(), error_kind)),
-            _ => Err(self.error_end(error_kind)),
-        }
-    }
-
     fn print_statement(&mut self, print_token: Token<'src>, chunk: &mut Chunk) -> Result<'src, ()> {
         self.expression(chunk)?;
-        chunk.add_op(Op::Print, print_token.line);
-        self.must_consume(TokenType::Semicolon, ParseErrorKind::NoSemicolonAfterValue).map(|_| ())
+        chunk.add_op(Op::Print, print_token.source_span());
+        self.cursor.must_consume(TokenType::Semicolon, ParseErrorKind::NoSemicolonAfterValue).map(|_| ())
     }
 
     fn block(&mut self, chunk: &mut Chunk) -> Result<'src, ()> {
-        self.compiler.enter_scope();
+        self.scopes.enter();
         loop {
-            match self.scanner.peek() {
-                Some(token) if token.ttype == TokenType::RightBrace => {
-                    let token = self.scanner.next().unwrap();
-                    let pop_count = self.compiler.exit_scope();
-                    for _ in 0..pop_count {
-                        chunk.add_op(Op::Pop, token.line);
-                    }
-                    break Ok(());
-                },
-                Some(_) => self.declaration(chunk),
-                None => {
-                    break Err(self.error_end(ParseErrorKind::RightBraceAfterBlock));
+            if let Some(token) = self.cursor.tokens.next_if(|token| token.ttype == TokenType::RightBrace) {
+                let pop_count = self.scopes.exit();
+                for _ in 0..pop_count {
+                    chunk.add_op(Op::Pop, token.source_span());
                 }
+                break Ok(());
+            }
+
+            if self.cursor.tokens.peek().is_none() {
+                break Err(self.cursor.error_end(ParseErrorKind::RightBraceAfterBlock));
             }
+
+            self.declaration(chunk);
         }
     }
 
     fn expr_statement(&mut self, chunk: &mut Chunk) -> Result<'src, ()> {
         self.expression(chunk)?;
-        let pop_line =
-            self.must_consume(TokenType::Semicolon, ParseErrorKind::NoSemicolonAfterExpression)
-                .map(|tok| tok.line)?;
-        chunk.add_op(Op::Pop, pop_line);
+        let pop_span =
+            self.cursor.must_consume(TokenType::Semicolon, ParseErrorKind::NoSemicolonAfterExpression)
+                .map(|tok| tok.source_span())?;
+        chunk.add_op(Op::Pop, pop_span);
 
         Ok(())
     }
 
     fn statement(&mut self, chunk: &mut Chunk) -> Result<'src, ()> {
-        match self.scanner.peek().unwrap().ttype {
-            TokenType::Print => {
-                let print_token = self.scanner.next().unwrap();
-                self.print_statement(print_token, chunk)
-            },
-            TokenType::LeftBrace => {
-                self.scanner.next();
-                self.block(chunk)
-            },
-            _ => self.expr_statement(chunk),
-        }
-    }
-
-    fn synchronize(&mut self) {
-        use TokenType::*;
-
-        while let Some(peek) = self.scanner.peek() {
-            if peek.ttype == TokenType::Semicolon {
-                self.scanner.next();
-                return;
-            }
-
-            if [Class, Fun, Var, For, If, While, Print, Return].contains(&peek.ttype) {
-                return;
-            }
-
-            self.scanner.next();
-        }
-    }
-
-    fn variable(&mut self) -> Result<'src, Token<'src>> {
-        let ident = self.must_consume(TokenType::Identifier, ParseErrorKind::NoVariableName)?;
-
-        if ident.span == "nil" {
-            Err(self.error_at(ident, ParseErrorKind::InvalidVariableName))
+        if let Some(print_token) = self.cursor.tokens.next_if(|token| token.ttype == TokenType::Print) {
+            self.print_statement(print_token, chunk)
+        } else if self.cursor.tokens.next_if(|token| token.ttype == TokenType::LeftBrace).is_some() {
+            self.nested(|parser| parser.block(chunk))
         } else {
-            Ok(ident)
+            self.expr_statement(chunk)
         }
     }
 
     fn var_declaration(&mut self, var_token: Token<'src>, chunk: &mut Chunk) ->  Result<'src, ()> {
-        let ident = self.variable()?;
+        let ident = self.cursor.variable_name()?;
 
-        if !self.compiler.in_global_scope() {
-            self.compiler.declare_local(ident.span).map_err(
-                |err| match err {
-                    LocalsError::TooMany => self.error_at(ident.clone(), ParseErrorKind::TooManyLocals),
-                    LocalsError::DuplicateInScope => self.error_at(ident.clone(), ParseErrorKind::DuplicateLocalInScope)
-                }
-            )?
-        }
+        let global_slot = if self.scopes.in_global_scope() {
+            Some(global_slot(&mut chunk.globals, &ident)?)
+        } else {
+            self.scopes.declare(&ident)?;
+            None
+        };
 
-        match self.scanner.peek() {
+        match self.cursor.tokens.peek() {
             Some(token) if token.ttype == TokenType::Equal => {
-                self.scanner.next();
+                self.cursor.tokens.next();
                 self.expression(chunk)?;
             },
             _ => {
-                chunk.add_op(Op::Nil, ident.line);
+                chunk.add_op(Op::Nil, ident.source_span());
             }
         }
 
-        if self.compiler.in_global_scope() {
-            let offset = self.add_string(chunk, ident.span);
-            chunk.add_op(Op::DefineGlobal { offset }, var_token.line);
+        if let Some(slot) = global_slot {
+            chunk.add_op(Op::DefineGlobal { slot }, var_token.source_span());
         } else {
-            self.compiler.mark_last_initialized();
+            self.scopes.mark_last_initialized();
         }
 
-        self.must_consume(TokenType::Semicolon, ParseErrorKind::NoSemicolonAfterVarDecl)?;
+        self.cursor.must_consume(TokenType::Semicolon, ParseErrorKind::NoSemicolonAfterVarDecl)?;
 
         Ok(())
     }
 
     pub fn declaration(&mut self, chunk: &mut Chunk) {
-        let peeked = self.scanner.peek().unwrap().clone();
-        let result = match peeked.ttype {
-            TokenType::Var => {
-                self.scanner.next();
-                self.var_declaration(peeked, chunk)
-            },
-            _ => self.statement(chunk),
+        let result = match self.cursor.tokens.next_if(|token| token.ttype == TokenType::Var) {
+            Some(var_token) => self.var_declaration(var_token, chunk),
+            None => self.statement(chunk),
         };
 
-        if let Err(err) = result {
-            self.errors.push(err);
-            self.synchronize();
-        }
+        self.cursor.end_declaration(result);
     }
 
     pub fn compile(&mut self, chunk: &mut Chunk) {
-        while let Some(_) = self.scanner.peek() {
+        while self.cursor.tokens.peek().is_some() {
             self.declaration(chunk)
         }
+
+        self.cursor.report_scan_errors();
     }
 }
 
@@ -789,10 +1269,18 @@ pub fn compile_expr<'src>(source: &'src str, chunk: &mut Chunk) -> Result<'src,
 }
 
 pub fn compile<'src>(source: &'src str, chunk: &mut Chunk) -> Vec<ParseError<'src>> {
+    compile_with(source, chunk, opt::Options::default())
+}
+
+/// `compile` with some optimizations turned off.
+pub fn compile_with<'src>(source: &'src str, chunk: &mut Chunk, options: opt::Options) -> Vec<ParseError<'src>> {
     let scanner = Scanner::new(source);
     let mut parser = Parser::new(scanner);
     parser.compile(chunk);
-    return parser.errors;
+    if parser.cursor.errors.is_empty() {
+        opt::optimize(chunk, options);
+    }
+    parser.cursor.errors
 }
 
 #[cfg(test)]
@@ -817,46 +1305,64 @@ mod tests {
                     ttype: TokenType::Print,
                     span: "print",
                     line: 1,
+                    column: 1,
+                    offset: 0,
                 },
                 Token {
                     ttype: TokenType::LeftParen,
                     span: "(",
                     line: 1,
+                    column: 6,
+                    offset: 5,
                 },
                 Token {
                     ttype: TokenType::Number,
                     span: "1",
                     line: 1,
+                    column: 7,
+                    offset: 6,
                 },
                 Token {
                     ttype: TokenType::Plus,
                     span: "+",
                     line: 1,
+                    column: 8,
+                    offset: 7,
                 },
                 Token {
                     ttype: TokenType::Number,
                     span: "2",
                     line: 1,
+                    column: 9,
+                    offset: 8,
                 },
                 Token {
                     ttype: TokenType::Star,
                     span: "*",
                     line: 1,
+                    column: 10,
+                    offset: 9,
                 },
                 Token {
                     ttype: TokenType::Number,
                     span: "3",
                     line: 1,
+                    column: 11,
+                    offset: 10,
                 },
                 Token {
                     ttype: TokenType::RightParen,
                     span: ")",
                     line: 1,
+                    column: 12,
+                    offset: 11,
                 },
                 Token {
                     ttype: TokenType::Semicolon,
                     span: ";",
                     line: 1,
+                    column: 13,
+                    offset: 12,
                 }
             ]
         );
@@ -886,16 +1392,22 @@ mod tests {
                     ttype: TokenType::Number,
                     span: "1",
                     line: 1,
+                    column: 1,
+                    offset: 0,
                 },
                 Token {
                     ttype: TokenType::Number,
                     span: "2",
                     line: 2,
+                    column: 1,
+                    offset: 2,
                 },
                 Token {
                     ttype: TokenType::Number,
                     span: "3",
                     line: 3,
+                    column: 1,
+                    offset: 13,
                 }
             ]
         );
@@ -913,12 +1425,386 @@ mod tests {
                 ttype: TokenType::String,
                 span: "\"hello world\"",
                 line: 1,
+                column: 1,
+                offset: 0,
             }]
         );
 
         assert_eq!(tokens[0].span, source);
     }
 
+    #[test]
+    fn columns_count_characters() {
+        let source = "\"h\u{e9}llo\" x\n  y";
+        let spans: Vec<SourceSpan> = Scanner::new(source).map(|token| token.source_span()).collect();
+
+        assert_eq!(
+            spans,
+            vec![
+                SourceSpan { line: 1, column: 1, offset: 0, len: 8 },
+                SourceSpan { line: 1, column: 9, offset: 9, len: 1 },
+                SourceSpan { line: 2, column: 3, offset: 13, len: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn error_at_end_points_past_last_character() {
+        let mut chunk = Chunk::new();
+        let errors = compile("print 1;\nprint", &mut chunk);
+
+        assert_eq!(errors[0].span, SourceSpan { line: 2, column: 6, offset: 14, len: 0 });
+    }
+
+    #[test]
+    fn unexpected_character_scan() {
+        let tokens: Vec<Token> = Scanner::new("a @ _b").collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token {
+                    ttype: TokenType::Identifier,
+                    span: "a",
+                    line: 1,
+                    column: 1,
+                    offset: 0,
+                },
+                Token {
+                    ttype: TokenType::Error(ScanErrorKind::UnexpectedCharacter),
+                    span: "@",
+                    line: 1,
+                    column: 3,
+                    offset: 2,
+                },
+                Token {
+                    ttype: TokenType::Identifier,
+                    span: "_b",
+                    line: 1,
+                    column: 5,
+                    offset: 4,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_number_scan() {
+        let tokens: Vec<Token> = Scanner::new("12ab").collect();
+
+        assert_eq!(
+            tokens,
+            vec![Token {
+                ttype: TokenType::Error(ScanErrorKind::InvalidDigit),
+                span: "12ab",
+                line: 1,
+                column: 1,
+                offset: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let source = "\u{3b1}\u{3bb}\u{3c6}\u{3b1} \u{43f}\u{440}\u{438}\u{432}\u{435}\u{442}_1 \u{53d8}\u{91cf} \u{928}\u{92e}\u{938}\u{94d}\u{924}\u{947} _\u{e9}t\u{e9} print";
+        let tokens: Vec<(TokenType, &str, usize, usize)> = Scanner::new(source)
+            .map(|token| (token.ttype, token.span, token.column, token.offset))
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Identifier, "\u{3b1}\u{3bb}\u{3c6}\u{3b1}", 1, 0),
+                (TokenType::Identifier, "\u{43f}\u{440}\u{438}\u{432}\u{435}\u{442}_1", 6, 9),
+                (TokenType::Identifier, "\u{53d8}\u{91cf}", 15, 24),
+                (TokenType::Identifier, "\u{928}\u{92e}\u{938}\u{94d}\u{924}\u{947}", 18, 31),
+                (TokenType::Identifier, "_\u{e9}t\u{e9}", 25, 50),
+                (TokenType::Print, "print", 30, 57),
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_byte_characters_end_tokens() {
+        let tokens: Vec<(TokenType, &str)> = Scanner::new("1\u{e9} \u{2603}\u{2603} \"\u{1f600}")
+            .map(|token| (token.ttype, token.span))
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Error(ScanErrorKind::InvalidDigit), "1\u{e9}"),
+                (TokenType::Error(ScanErrorKind::UnexpectedCharacter), "\u{2603}"),
+                (TokenType::Error(ScanErrorKind::UnexpectedCharacter), "\u{2603}"),
+                (TokenType::Error(ScanErrorKind::UndelimitedString), "\"\u{1f600}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_variables_compile() {
+        let mut chunk = Chunk::new();
+        let errors = compile("var \u{540d}\u{524d} = 1; { var \u{3bb} = \u{540d}\u{524d}; print \u{3bb}; }", &mut chunk);
+
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn escaped_quote_does_not_end_string() {
+        let spans: Vec<&str> = Scanner::new(r#""say \"hi\"" "\\" x"#).map(|token| token.span).collect();
+
+        assert_eq!(spans, vec![r#""say \"hi\"""#, r#""\\""#, "x"]);
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(unescape("plain"), Ok(Cow::Borrowed("plain")));
+        assert_eq!(
+            unescape(r#"a\n\t\\\"\u{48}\u{1F600}"#),
+            Ok(Cow::Owned("a\n\t\\\"H\u{1f600}".to_string()))
+        );
+
+        assert_eq!(unescape(r"ab\q"), Err((2..4, ParseErrorKind::InvalidEscape)));
+        assert_eq!(unescape(r"\u41"), Err((0..2, ParseErrorKind::InvalidUnicodeEscape)));
+        assert_eq!(unescape(r"\u{}"), Err((0..4, ParseErrorKind::InvalidUnicodeEscape)));
+        assert_eq!(unescape(r"\u{4g}"), Err((0..4, ParseErrorKind::InvalidUnicodeEscape)));
+        assert_eq!(unescape(r"\u{D800}"), Err((0..8, ParseErrorKind::InvalidUnicodeEscape)));
+        assert_eq!(unescape(r"\u{0000041}"), Err((0..11, ParseErrorKind::InvalidUnicodeEscape)));
+    }
+
+    #[test]
+    fn invalid_escape_points_into_multi_line_string() {
+        let mut chunk = Chunk::new();
+        let errors = compile("print 1;\nprint \"first\n  second \\q\";", &mut chunk);
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::InvalidEscape);
+        assert_eq!(errors[0].span, SourceSpan { line: 3, column: 10, offset: 31, len: 2 });
+        assert_eq!(errors[0].to_string(), "[line 3] Error at '\\q': Invalid escape sequence.");
+    }
+
+    #[test]
+    fn multi_line_string_keeps_later_lines() {
+        let tokens: Vec<(TokenType, usize)> = Scanner::new("\"a\nb\"\n;")
+            .map(|token| (token.ttype, token.line))
+            .collect();
+
+        assert_eq!(tokens, vec![(TokenType::String, 1), (TokenType::Semicolon, 3)]);
+    }
+
+    #[test]
+    fn number_literals() {
+        let cases = [
+            ("123", 123.0),
+            ("007", 7.0),
+            ("1.5", 1.5),
+            ("0xFF", 255.0),
+            ("0Xa_b", 171.0),
+            ("0b1010", 10.0),
+            ("1_000_000", 1_000_000.0),
+            ("1e-9", 1e-9),
+            ("2.5E+3", 2500.0),
+            ("1_0e1_0", 10e10),
+        ];
+
+        for (literal, value) in cases {
+            assert_eq!(number_value(literal), Ok(value), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn malformed_number_literals() {
+        let cases = [
+            ("12ab", ScanErrorKind::InvalidDigit),
+            ("0b102", ScanErrorKind::InvalidDigit),
+            ("0xFG", ScanErrorKind::InvalidDigit),
+            ("0x", ScanErrorKind::MissingDigits),
+            ("0b_", ScanErrorKind::MisplacedSeparator),
+            ("1e", ScanErrorKind::MissingExponentDigits),
+            ("1e+", ScanErrorKind::MissingExponentDigits),
+            ("1_", ScanErrorKind::MisplacedSeparator),
+            ("1__0", ScanErrorKind::MisplacedSeparator),
+            ("1_.5", ScanErrorKind::MisplacedSeparator),
+            ("0x_1", ScanErrorKind::MisplacedSeparator),
+            ("1e999", ScanErrorKind::NumberTooLarge),
+            ("0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF", ScanErrorKind::NumberTooLarge),
+        ];
+
+        for (literal, kind) in cases {
+            let tokens: Vec<(TokenType, &str)> =
+                Scanner::new(literal).map(|token| (token.ttype, token.span)).collect();
+            assert_eq!(tokens, vec![(TokenType::Error(kind), literal)]);
+        }
+    }
+
+    #[test]
+    fn number_boundaries() {
+        let tokens: Vec<(TokenType, &str)> = Scanner::new("1.x 1e-9; 0x1e-1 2.")
+            .map(|token| (token.ttype, token.span))
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Number, "1"),
+                (TokenType::Dot, "."),
+                (TokenType::Identifier, "x"),
+                (TokenType::Number, "1e-9"),
+                (TokenType::Semicolon, ";"),
+                (TokenType::Number, "0x1e"),
+                (TokenType::Minus, "-"),
+                (TokenType::Number, "1"),
+                (TokenType::Number, "2"),
+                (TokenType::Dot, "."),
+            ]
+        );
+    }
+
+    #[test]
+    fn error_kind_messages() {
+        let scan_errors = [
+            (ScanErrorKind::UndelimitedString, "Unterminated string."),
+            (ScanErrorKind::UnexpectedCharacter, "Unexpected character."),
+            (ScanErrorKind::InvalidDigit, "Invalid digit in number literal."),
+            (ScanErrorKind::MissingDigits, "Expect digits after number prefix."),
+            (ScanErrorKind::MissingExponentDigits, "Expect digits in exponent."),
+            (ScanErrorKind::MisplacedSeparator, "Digit separator '_' must be between digits."),
+            (ScanErrorKind::NumberTooLarge, "Number literal is too large."),
+            (ScanErrorKind::UnterminatedComment, "Unterminated block comment."),
+        ];
+        for (kind, message) in scan_errors {
+            assert_eq!(kind.to_string(), message);
+            assert_eq!(ParseErrorKind::ScanError(kind).to_string(), message);
+        }
+
+        let parse_errors = [
+            (ParseErrorKind::InvalidNumber, "Invalid number literal."),
+            (ParseErrorKind::IncompleteExpression, "Expect expression."),
+            (ParseErrorKind::NoSemicolonAfterValue, "Expect ';' after value."),
+            (ParseErrorKind::NoSemicolonAfterExpression, "Expect ';' after expression."),
+            (ParseErrorKind::NoVariableName, "Expect variable name."),
+            (ParseErrorKind::NoSemicolonAfterVarDecl, "Expect ';' after variable declaration."),
+            (ParseErrorKind::InvalidAssignmentTarget, "Invalid assignment target."),
+            (ParseErrorKind::InvalidVariableName, "Expect variable name."),
+            (ParseErrorKind::RightBraceAfterBlock, "Expect '}' after block."),
+            (ParseErrorKind::TooManyLocals, "Too many local variables in function."),
+            (ParseErrorKind::DuplicateLocalInScope, "Already a variable with this name in this scope."),
+            (ParseErrorKind::LocalInOwnInitializer, "Can't read local variable in its own initializer."),
+            (ParseErrorKind::TooManyArguments, "Can't have more than 255 arguments."),
+            (ParseErrorKind::NoRightParenAfterArguments, "Expect ')' after arguments."),
+            (ParseErrorKind::InvalidEscape, "Invalid escape sequence."),
+            (ParseErrorKind::InvalidUnicodeEscape, "Invalid unicode escape sequence."),
+            (ParseErrorKind::NoRightParenAfterExpression, "Expect ')' after expression."),
+            (ParseErrorKind::TooManyConstants, "Too many constants in one chunk."),
+            (ParseErrorKind::TooDeeplyNested, "Too much nesting."),
+            (ParseErrorKind::TooManyRegisters, "Too many registers in one chunk."),
+            (ParseErrorKind::TooManyGlobals, "Too many global variables in one chunk."),
+        ];
+        for (kind, message) in parse_errors {
+            assert_eq!(kind.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn nested_block_comments() {
+        let source = "a /* one /* two\n*/ still\n comment */ b /*/ x */ c /**/ d";
+        let tokens: Vec<(&str, usize, usize)> = Scanner::new(source)
+            .map(|token| (token.span, token.line, token.column))
+            .collect();
+
+        assert_eq!(tokens, vec![("a", 1, 1), ("b", 3, 13), ("c", 3, 24), ("d", 3, 31)]);
+    }
+
+    #[test]
+    fn unterminated_block_comment() {
+        let mut chunk = Chunk::new();
+        let errors = compile("print 1;\n  /* a /* b */\n print 2;", &mut chunk);
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::ScanError(ScanErrorKind::UnterminatedComment));
+        assert_eq!(errors[0].span, SourceSpan { line: 2, column: 3, offset: 11, len: 2 });
+    }
+
+    #[test]
+    fn malformed_input_reports_errors() {
+        let cases = [
+            ("print (1 + 2", "[line 1] Error at end: Expect ')' after expression."),
+            ("print (1 + 2;", "[line 1] Error at ';': Expect ')' after expression."),
+            ("var a; a = 1 = 2;", "[line 1] Error at '=': Invalid assignment target."),
+            ("1 + 2 = 3;", "[line 1] Error at '=': Invalid assignment target."),
+            ("{ print", "[line 1] Error at end: Expect expression."),
+            ("f(1,", "[line 1] Error at end: Expect expression."),
+        ];
+
+        for (source, message) in cases {
+            let mut chunk = Chunk::new();
+            let errors = compile(source, &mut chunk);
+            assert_eq!(errors.first().map(|err| err.to_string()).as_deref(), Some(message), "{}", source);
+        }
+    }
+
+    #[test]
+    fn too_many_constants() {
+        let source: String = (0..300).map(|i| format!("print {};", i)).collect();
+        let mut chunk = Chunk::new();
+        let errors = compile(&source, &mut chunk);
+
+        assert_eq!(errors.len(), 44);
+        assert_eq!(errors[0].kind, ParseErrorKind::TooManyConstants);
+        assert_eq!(errors[0].location.as_ref().map(|token| token.span), Some("256"));
+    }
+
+    #[test]
+    fn too_many_globals() {
+        let source: String = (0..300).map(|i| format!("var g{};", i)).collect();
+        let mut chunk = Chunk::new();
+        let errors = compile(&source, &mut chunk);
+
+        // The natives take the first 4 of the 256 slots.
+        assert_eq!(errors.len(), 48);
+        assert_eq!(errors[0].kind, ParseErrorKind::TooManyGlobals);
+        assert_eq!(errors[0].location.as_ref().map(|token| token.span), Some("g252"));
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let depth = 100_000;
+        let cases = [
+            format!("print {}1{};", "(".repeat(depth), ")".repeat(depth)),
+            format!("print {}1;", "-".repeat(depth)),
+            format!("{}{}", "{".repeat(depth), "}".repeat(depth)),
+        ];
+
+        for source in cases {
+            let mut chunk = Chunk::new();
+            let errors = compile(&source, &mut chunk);
+            assert_eq!(errors[0].kind, ParseErrorKind::TooDeeplyNested);
+        }
+
+        let mut chunk = Chunk::new();
+        let nested = format!("print {}1{};", "(".repeat(MAX_NESTING - 1), ")".repeat(MAX_NESTING - 1));
+        assert!(compile(&nested, &mut chunk).is_empty());
+    }
+
+    #[test]
+    fn scan_errors_are_reported_and_parsing_continues() {
+        let source = "print 1;\nprint # 2;\nvar;\n@";
+        let mut chunk = Chunk::new();
+        let errors = compile(source, &mut chunk);
+
+        let kinds: Vec<&ParseErrorKind> = errors.iter().map(|err| &err.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                &ParseErrorKind::ScanError(ScanErrorKind::UnexpectedCharacter),
+                &ParseErrorKind::NoVariableName,
+                &ParseErrorKind::ScanError(ScanErrorKind::UnexpectedCharacter),
+            ]
+        );
+        assert_eq!(errors[0].to_string(), "[line 2] Error: Unexpected character.");
+        assert_eq!(errors[2].span, SourceSpan { line: 4, column: 1, offset: 25, len: 1 });
+    }
+
     fn test_parse_expression(source: &str, expected: &Chunk) {
         let scanner = Scanner::new(source);
         let mut parser = Parser::new(scanner);
@@ -928,13 +1814,13 @@ mod tests {
         assert!(chunk.instr_eq(expected));
     }
 
-    fn test_parse_program<'src>(source: &'src str, expected: &Chunk) {
+    fn test_parse_program(source: &str, expected: &Chunk) {
         let scanner = Scanner::new(source);
         let mut parser = Parser::new(scanner);
         let mut chunk = Chunk::new();
         parser.compile(&mut chunk);
 
-        assert_eq!(parser.errors, vec![]);
+        assert_eq!(parser.cursor.errors, vec![]);
         assert!(chunk.instr_eq(expected));
     }
 
@@ -989,8 +1875,8 @@ mod tests {
         use crate::bc::Op::*;
         let expected = Chunk::new_with(
             vec![
-                False, Not, True, Not, True, Less, Not, False, Greater, Not, True, Greater, False,
-                Less, Equal, True, Equal, Not,
+                False, Not, True, Not, True, GreaterEqual, False, LessEqual, True, Greater, False,
+                Less, Equal, True, NotEqual,
             ],
             vec![],
             vec![],
@@ -1063,13 +1949,13 @@ mod tests {
     fn basic_var_decl() {
         let source = "var x;";
         use crate::bc::Op::*;
-        let x = GC::new_string("x");
-        let expected = Chunk::new_with(
-            vec![Nil, DefineGlobal { offset: 0 }],
+        let mut expected = Chunk::new_with(
+            vec![Nil, DefineGlobal { slot: 4 }],
             vec![1, 1],
-            vec![x.get_object().into()],
+            vec![],
             LinkedList::new(),
         );
+        expected.globals.slot("x");
 
         test_parse_program(source, &expected);
     }
@@ -1078,13 +1964,13 @@ mod tests {
     fn basic_var_decl_with_initializer() {
         let source = "var x = 1 + 1;";
         use crate::bc::Op::*;
-        let x = GC::new_string("x");
-        let expected = Chunk::new_with(
-            vec![Constant {offset: 1}, Constant {offset: 2}, Add, DefineGlobal { offset: 0 }],
+        let mut expected = Chunk::new_with(
+            vec![Constant {offset: 0}, Constant {offset: 1}, Add, DefineGlobal { slot: 4 }],
             vec![1, 1, 1, 1],
-            vec![x.get_object().into(), Value::from(1.0), Value::from(1.0)],
+            vec![Value::from(1.0), Value::from(1.0)],
             LinkedList::new(),
         );
+        expected.globals.slot("x");
 
         test_parse_program(source, &expected);
     }
@@ -1093,19 +1979,33 @@ mod tests {
     fn assign() {
         let source = "var x = y = z;";
         use crate::bc::Op::*;
-        let x = GC::new_string("x");
-        let y = GC::new_string("y");
-        let z = GC::new_string("z");
-        let expected = Chunk::new_with(
-            vec![GetGlobal { offset: 2 }, SetGlobal { offset: 1 }, DefineGlobal { offset: 0 }],
+        let mut expected = Chunk::new_with(
+            vec![GetGlobal { slot: 6 }, SetGlobal { slot: 5 }, DefineGlobal { slot: 4 }],
             vec![1, 1, 1],
-            vec![x.get_object().into(), y.get_object().into(), z.get_object().into()],
+            vec![],
             LinkedList::new(),
         );
+        for name in ["x", "y", "z"] {
+            expected.globals.slot(name);
+        }
 
         test_parse_program(source, &expected);
     }
 
+    #[test]
+    fn duplicate_local_points_at_previous_declaration() {
+        let mut chunk = Chunk::new();
+        let errors = compile("{\n  var a;\n  var a;\n}", &mut chunk);
+
+        assert_eq!(errors[0].kind, ParseErrorKind::DuplicateLocalInScope);
+        assert_eq!(
+            errors[0].labels,
+            vec![(SourceSpan { line: 2, column: 7, offset: 8, len: 1 }, "previous declaration here")]
+        );
+        assert_eq!(errors[0].diagnostic().labels.len(), 2);
+        assert_eq!(errors[0].diagnostic().code, "E0110");
+    }
+
     #[test]
     fn block_missing_brace() {
         let source = "{ var a; ";
@@ -1114,6 +2014,6 @@ mod tests {
         let mut chunk = Chunk::new();
         parser.compile(&mut chunk);
 
-        assert_eq!(parser.errors[0].kind, ParseErrorKind::RightBraceAfterBlock)
+        assert_eq!(parser.cursor.errors[0].kind, ParseErrorKind::RightBraceAfterBlock)
     }
 }
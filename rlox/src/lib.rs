@@ -0,0 +1,24 @@
+//! A bytecode virtual machine for Lox, from the second part of Crafting
+//! Interpreters.
+//!
+//! So far rlox compiles expressions, `print` and `var` statements, blocks and
+//! calls to the natives in `natives`. Functions, classes, control flow and
+//! loops are not implemented yet.
+
+pub mod ast;
+pub mod bc;
+pub mod bench;
+pub mod codegen;
+pub mod diagnostics;
+pub mod fuzz;
+pub mod gc;
+pub mod lc;
+#[cfg(feature = "nan-boxing")]
+mod nanbox;
+pub mod natives;
+pub mod opt;
+pub mod reg;
+pub mod regcodegen;
+mod regvm;
+pub mod resolver;
+pub mod vm;
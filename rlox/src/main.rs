@@ -1,49 +1,81 @@
-mod bc;
-mod lc;
-mod vm;
-mod gc;
-
 use std::env;
 use std::fs;
-use std::io;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 
-use bc::Chunk;
-use vm::VM;
+use rlox::bc::Chunk;
+use rlox::bench::{self, Failure};
+use rlox::diagnostics::{Diagnostic, ErrorFormat};
+use rlox::lc;
+use rlox::opt;
+use rlox::reg;
+use rlox::regcodegen;
+use rlox::vm::VM;
+
+const USAGE: &str = "Usage: rlox [--error-format=short|rich|json] [--no-superinstructions] [--registers] [path [args...]]
+       rlox [--no-superinstructions] bench [--runs=N] [--threshold=PERCENT] [--baseline=FILE] [--save-baseline=FILE] [path...]";
+
+struct Options {
+    error_format: ErrorFormat,
+    do_trace: bool,
+    optimizations: opt::Options,
+    /// Compile to and run the register instructions in `reg`.
+    registers: bool,
+}
 
+fn report(options: &Options, source: &str, path: &str, short: &dyn std::fmt::Display, diagnostic: Diagnostic) {
+    match options.error_format {
+        ErrorFormat::Short => eprintln!("{}", short),
+        ErrorFormat::Rich => {
+            let color = io::stderr().is_terminal();
+            eprint!("{}", diagnostic.render(source, path, color))
+        }
+        ErrorFormat::Json => eprintln!("{}", diagnostic.to_json(path)),
+    }
+}
 
-fn compile_and_run(source: &str, do_trace: bool) -> ExitCode {
+fn compile_and_run(source: &str, path: &str, script_args: Vec<String>, options: &Options) -> ExitCode {
     let mut chunk = Chunk::new();
-    let errors = lc::compile(source, &mut chunk);
+    let mut reg_chunk = reg::Chunk::new();
+    let errors = if options.registers {
+        regcodegen::compile(source, &mut reg_chunk)
+    } else {
+        lc::compile_with(source, &mut chunk, options.optimizations)
+    };
 
     if errors.is_empty() {
         let mut vm = VM::new();
-        vm.set_trace(do_trace);
-        if let Err(err) = vm.stdrun(&chunk) {
-            eprintln!("{}", err);
+        vm.set_trace(options.do_trace);
+        vm.set_script_args(script_args);
+        let result = if options.registers {
+            vm.stdrun_registers(&reg_chunk)
+        } else {
+            vm.stdrun(&chunk)
+        };
+        if let Err(err) = result {
+            report(options, source, path, &err, err.diagnostic());
             ExitCode::from(70)
         } else {
-            ExitCode::from(0)
+            ExitCode::from(vm.exit_code().unwrap_or(0))
         }
 
     } else {
         for error in errors {
-            eprintln!("{}", error);
+            report(options, source, path, &error, error.diagnostic());
         }
         ExitCode::from(65)
     }
 }
 
 
-fn repl() {
+fn repl(options: &Options) {
     let mut buffer = String::new();
 
-    let do_trace = env::var("LOX_TRACE").is_ok();
-
     loop {
         match io::stdin().read_line(&mut buffer) {
             Ok(_) => {
-                compile_and_run(buffer.as_str(), do_trace);
+                compile_and_run(buffer.as_str(), "<stdin>", Vec::new(), options);
                 buffer.clear();
             }
             Err(error) => println!("{:?}", error),
@@ -51,23 +83,205 @@ fn repl() {
     }
 }
 
-fn run_file(path: String) -> ExitCode {
-    let do_trace = env::var("LOX_TRACE").is_ok();
-    let source = fs::read_to_string(path).unwrap();
-    compile_and_run(source.as_str(), do_trace)
+fn run_file(path: String, script_args: Vec<String>, options: &Options) -> ExitCode {
+    let source = fs::read_to_string(&path).unwrap();
+    compile_and_run(source.as_str(), &path, script_args, options)
+}
+
+struct BenchOptions {
+    runs: usize,
+    /// Slowdown of the minimum time that counts as a regression, as a fraction.
+    threshold: f64,
+    baseline: Option<String>,
+    save_baseline: Option<String>,
+    paths: Vec<String>,
+}
+
+impl BenchOptions {
+    fn parse(args: impl Iterator<Item = String>) -> Option<BenchOptions> {
+        let mut options = BenchOptions {
+            runs: 10,
+            threshold: 0.1,
+            baseline: None,
+            save_baseline: None,
+            paths: Vec::new(),
+        };
+
+        for arg in args {
+            if let Some(runs) = arg.strip_prefix("--runs=") {
+                options.runs = runs.parse().ok().filter(|&runs| runs > 0)?;
+            } else if let Some(percent) = arg.strip_prefix("--threshold=") {
+                options.threshold = percent.parse::<f64>().ok().filter(|percent| *percent >= 0.0)? / 100.0;
+            } else if let Some(path) = arg.strip_prefix("--baseline=") {
+                options.baseline = Some(path.to_string());
+            } else if let Some(path) = arg.strip_prefix("--save-baseline=") {
+                options.save_baseline = Some(path.to_string());
+            } else if arg.starts_with("--") {
+                return None;
+            } else {
+                options.paths.push(arg);
+            }
+        }
+
+        if options.paths.is_empty() {
+            options.paths.push("benchmarks".to_string());
+        }
+        Some(options)
+    }
+}
+
+/// The `.lox` files in `path`, or `path` itself if it is a file.
+fn benchmark_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|ext| ext == "lox") {
+            benchmark_files(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Times every benchmark, compared with the baseline if there is one. Fails
+/// if any benchmark did not compile or run, or regressed; benchmarks using
+/// parts of Lox that rlox does not compile yet are reported as unsupported.
+fn run_benchmarks(bench_options: BenchOptions, options: &Options) -> ExitCode {
+    let baseline = match &bench_options.baseline {
+        None => Vec::new(),
+        Some(path) => match fs::read_to_string(path).map_err(|err| err.to_string()).and_then(|json| bench::parse_baseline(&json)) {
+            Ok(baseline) => baseline,
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                return ExitCode::from(66);
+            }
+        },
+    };
+
+    let mut files = Vec::new();
+    for path in &bench_options.paths {
+        if let Err(err) = benchmark_files(Path::new(path), &mut files) {
+            eprintln!("{}: {}", path, err);
+            return ExitCode::from(66);
+        }
+    }
+
+    if files.is_empty() {
+        eprintln!("{}: no benchmarks", bench_options.paths.join(", "));
+        return ExitCode::from(66);
+    }
+
+    println!("{:<24} {:>10} {:>10} {:>14}", "benchmark", "min", "median", "instructions");
+
+    let mut measurements = Vec::new();
+    let mut failed = 0;
+    let mut regressed = 0;
+    for file in files {
+        let name = file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let result = fs::read_to_string(&file)
+            .map_err(|err| Failure::Error(err.to_string()))
+            .and_then(|source| bench::measure(&name, &source, bench_options.runs, options.optimizations));
+
+        let measurement = match result {
+            Ok(measurement) => measurement,
+            Err(Failure::Unsupported(err)) => {
+                println!("{:<24} unsupported: {}", name, err);
+                continue;
+            }
+            Err(Failure::Error(err)) => {
+                println!("{:<24} failed: {}", name, err);
+                failed += 1;
+                continue;
+            }
+        };
+
+        println!(
+            "{:<24} {:>10} {:>10} {:>14}",
+            name,
+            bench::format_duration(measurement.min),
+            bench::format_duration(measurement.median),
+            measurement.instructions
+        );
+
+        if let Some(previous) = baseline.iter().find(|previous| previous.name == name) {
+            let regressions = bench::regressions(&measurement, previous, bench_options.threshold);
+            for regression in &regressions {
+                println!("    regressed: {}", regression);
+            }
+            regressed += !regressions.is_empty() as usize;
+        }
+        measurements.push(measurement);
+    }
+
+    if let Some(path) = &bench_options.save_baseline {
+        if let Err(err) = fs::write(path, bench::to_json(&measurements)) {
+            eprintln!("{}: {}", path, err);
+            return ExitCode::from(74);
+        }
+    }
+
+    if failed > 0 {
+        println!("{} benchmark(s) failed", failed);
+    }
+    if regressed > 0 {
+        println!("{} benchmark(s) regressed", regressed);
+    }
+    if failed > 0 || regressed > 0 {
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
 }
 
 fn main() -> ExitCode {
-    let num_args = env::args().len();
-
-    if num_args == 1 {
-        repl();
-        ExitCode::SUCCESS
-    } else if num_args == 2 {
-        let source = env::args().nth(1).unwrap();
-        run_file(source)
-    } else {
-        println!("Usage: rlox [path]");
-        ExitCode::from(64)
+    let mut args = env::args().skip(1).peekable();
+    let mut options = Options {
+        error_format: ErrorFormat::default(),
+        do_trace: env::var("LOX_TRACE").is_ok(),
+        optimizations: opt::Options::default(),
+        registers: false,
+    };
+
+    while let Some(option) = args.next_if(|arg| arg.starts_with("--")) {
+        if option == "--no-superinstructions" {
+            options.optimizations.superinstructions = false;
+            continue;
+        }
+        if option == "--registers" {
+            options.registers = true;
+            continue;
+        }
+
+        let error_format = option
+            .strip_prefix("--error-format=")
+            .and_then(ErrorFormat::parse);
+
+        match error_format {
+            Some(error_format) => options.error_format = error_format,
+            None => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(64);
+            }
+        }
+    }
+
+    match args.next() {
+        None => {
+            repl(&options);
+            ExitCode::SUCCESS
+        }
+        Some(command) if command == "bench" => match BenchOptions::parse(args) {
+            Some(bench_options) => run_benchmarks(bench_options, &options),
+            None => {
+                eprintln!("{}", USAGE);
+                ExitCode::from(64)
+            }
+        },
+        Some(path) => run_file(path, args.collect(), &options),
     }
 }
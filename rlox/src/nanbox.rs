@@ -0,0 +1,162 @@
+//! A `Value` packed into a single `u64`, enabled by the `nan-boxing` feature.
+//!
+//! Numbers are stored as their IEEE 754 bits. Every other value hides in the
+//! payload of a quiet NaN, which no arithmetic operation produces:
+//!
+//! ```text
+//! number  any bits without all of QNAN set
+//! nil     QNAN | TAG_NIL
+//! false   QNAN | TAG_FALSE
+//! true    QNAN | TAG_TRUE
+//! object  SIGN_BIT | QNAN | address
+//! ```
+//!
+//! Object addresses must fit in the low 48 bits, which holds for user space
+//! pointers on x86-64 and AArch64.
+
+use std::fmt;
+
+use crate::gc::Object;
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const NIL: u64 = QNAN | TAG_NIL;
+const FALSE: u64 = QNAN | TAG_FALSE;
+const TRUE: u64 = QNAN | TAG_TRUE;
+
+/// Same API as the enum `bc::Value`.
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+impl Value {
+    pub fn nil() -> Value {
+        Value(NIL)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+
+    pub fn as_num(&self) -> Option<f64> {
+        if self.0 & QNAN != QNAN {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0 {
+            TRUE => Some(true),
+            FALSE => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<Object> {
+        if self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN {
+            Some(Object::from_bits(self.0 & !(SIGN_BIT | QNAN)))
+        } else {
+            None
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        let bits = value.to_bits();
+        if bits & QNAN == QNAN {
+            // A NaN whose bits would read as a tagged value.
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(bits)
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value(if value { TRUE } else { FALSE })
+    }
+}
+
+/// Relies on the address-space assumption in the module docs: every `Object`
+/// points into user space below 2^48, so its bits never overlap `SIGN_BIT |
+/// QNAN`. Debug builds check it; release builds trust it, as the check would
+/// otherwise run on every object pushed.
+impl From<Object> for Value {
+    fn from(value: Object) -> Self {
+        let bits = value.to_bits();
+        debug_assert!(bits & (SIGN_BIT | QNAN) == 0, "object address does not fit in a NaN box");
+        Value(SIGN_BIT | QNAN | bits)
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        if let (Some(a), Some(b)) = (self.as_num(), other.as_num()) {
+            return a == b;
+        }
+        if let (Some(a), Some(b)) = (self.as_obj(), other.as_obj()) {
+            return a == b;
+        }
+        self.0 == other.0
+    }
+}
+
+/// Same output as the enum's derived `Debug`.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(number) = self.as_num() {
+            f.debug_tuple("Number").field(&number).finish()
+        } else if let Some(boolean) = self.as_bool() {
+            f.debug_tuple("Bool").field(&boolean).finish()
+        } else if let Some(object) = self.as_obj() {
+            f.debug_tuple("Obj").field(&object).finish()
+        } else {
+            write!(f, "Nil")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gc::GC;
+
+    #[test]
+    fn round_trips() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+        let string = GC::new_string("boxed");
+
+        for number in [0.0, -0.0, 1.5, f64::INFINITY, f64::NEG_INFINITY, f64::MAX, f64::MIN_POSITIVE] {
+            assert_eq!(Value::from(number).as_num().map(f64::to_bits), Some(number.to_bits()));
+        }
+        assert_eq!(Value::from(true).as_bool(), Some(true));
+        assert_eq!(Value::from(false).as_bool(), Some(false));
+        assert_eq!(Value::from(string.get_object()).as_obj(), Some(string.get_object()));
+        assert!(Value::nil().is_nil());
+
+        let values = [Value::nil(), Value::from(true), Value::from(1.0), Value::from(string.get_object())];
+        for (i, value) in values.iter().enumerate() {
+            let kinds = [value.is_nil(), value.as_bool().is_some(), value.as_num().is_some(), value.as_obj().is_some()];
+            assert_eq!(kinds.iter().filter(|&&kind| kind).count(), 1, "{:?}", value);
+            assert!(kinds[i], "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn nans_stay_numbers() {
+        let tagged_nan = f64::from_bits(QNAN | TAG_TRUE);
+        for nan in [f64::NAN, -f64::NAN, tagged_nan, f64::from_bits(SIGN_BIT | QNAN)] {
+            let value = Value::from(nan);
+            assert!(value.as_num().unwrap().is_nan());
+            assert_ne!(value, value);
+        }
+    }
+}
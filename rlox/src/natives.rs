@@ -0,0 +1,143 @@
+use std::env;
+
+use crate::bc::Value;
+use crate::gc::ObjString;
+use crate::vm::{NativeFn, VMErrorKind, VM};
+
+type Result<T> = std::result::Result<T, crate::vm::VMError>;
+
+/// Native functions bound as globals in every VM: (name, arity, implementation).
+pub const NATIVES: [(&str, u8, NativeFn); 4] = [
+    ("argc", 0, argc),
+    ("argv", 1, argv),
+    ("getenv", 1, getenv),
+    ("exit", 1, exit),
+];
+
+fn string_argument(vm: &VM, value: &Value) -> Result<ObjString> {
+    value
+        .as_obj()
+        .and_then(|object| object.downcast::<ObjString>())
+        .ok_or_else(|| vm.err(VMErrorKind::ExpectedStringArgument(value.value_type())))
+}
+
+fn integer_argument(vm: &VM, value: &Value) -> Result<f64> {
+    match value.as_num() {
+        Some(num) if num.fract() == 0.0 => Ok(num),
+        _ => Err(vm.err(VMErrorKind::ExpectedIntegerArgument)),
+    }
+}
+
+/// Number of arguments following the script path on the command line.
+fn argc(vm: &mut VM, _args: &[Value]) -> Result<Value> {
+    Ok(Value::from(vm.script_args().len() as f64))
+}
+
+/// The i-th argument following the script path, or nil if out of range.
+fn argv(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let index = integer_argument(vm, &args[0])?;
+
+    if index < 0.0 {
+        return Ok(Value::nil());
+    }
+
+    match vm.script_args().get(index as usize).cloned() {
+        Some(arg) => Ok(vm.new_string(&arg)),
+        None => Ok(Value::nil()),
+    }
+}
+
+/// Value of the environment variable, or nil if unset or not unicode.
+fn getenv(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let name = string_argument(vm, &args[0])?.as_str();
+
+    if name.is_empty() || name.contains(['=', '\0']) {
+        return Ok(Value::nil());
+    }
+
+    match env::var(name) {
+        Ok(value) => Ok(vm.new_string(&value)),
+        Err(_) => Ok(Value::nil()),
+    }
+}
+
+/// Stops the program; the code becomes the process exit status.
+fn exit(vm: &mut VM, args: &[Value]) -> Result<Value> {
+    let code = integer_argument(vm, &args[0])?;
+
+    if !(0.0..=255.0).contains(&code) {
+        return Err(vm.err(VMErrorKind::InvalidExitCode));
+    }
+
+    vm.halt(code as u8);
+    Ok(Value::nil())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bc::{Chunk, SourceSpan, ValueType};
+    use crate::lc;
+    use crate::vm::{StackFrame, VMErrorKind, VM};
+
+    fn run(source: &str, script_args: &[&str]) -> (VM, Result<String, VMErrorKind>) {
+        let mut chunk = Chunk::new();
+        assert!(lc::compile(source, &mut chunk).is_empty());
+
+        let mut vm = VM::new();
+        vm.set_script_args(script_args.iter().map(|arg| arg.to_string()).collect());
+
+        let mut output = Vec::new();
+        let result = vm.run(&chunk, &mut output);
+        let result = result
+            .map(|_| String::from_utf8(output).unwrap())
+            .map_err(|err| err.kind);
+        (vm, result)
+    }
+
+    #[test]
+    fn script_arguments() {
+        let (_, output) = run("print argc(); print argv(0) + argv(1); print argv(2);", &["a", "b"]);
+        assert_eq!(output, Ok("2\nab\nnil\n".to_string()));
+    }
+
+    #[test]
+    fn exit_stops_execution() {
+        let (vm, output) = run("print 1; exit(3); print 2;", &[]);
+        assert_eq!(output, Ok("1\n".to_string()));
+        assert_eq!(vm.exit_code(), Some(3));
+    }
+
+    #[test]
+    fn native_frames_in_stack_trace() {
+        let mut chunk = Chunk::new();
+        assert!(lc::compile("print 1;\nexit(-1);", &mut chunk).is_empty());
+
+        let mut vm = VM::new();
+        let err = vm.run(&chunk, &mut Vec::new()).unwrap_err();
+        assert_eq!(vm.frames.len(), 1);
+        let call = SourceSpan { line: 2, column: 5, offset: 13, len: 1 };
+        assert_eq!(
+            err.trace,
+            vec![
+                StackFrame { span: call, function: Some("exit".to_string()) },
+                StackFrame { span: call, function: None },
+            ]
+        );
+        assert_eq!(err.diagnostic().labels.len(), 1);
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert_eq!(run("exit(256);", &[]).1, Err(VMErrorKind::InvalidExitCode));
+        assert_eq!(run("argv(\"0\");", &[]).1, Err(VMErrorKind::ExpectedIntegerArgument));
+        assert_eq!(
+            run("getenv(1);", &[]).1,
+            Err(VMErrorKind::ExpectedStringArgument(ValueType::Number))
+        );
+        assert_eq!(
+            run("argc(1);", &[]).1,
+            Err(VMErrorKind::ArityMismatch { expected: 0, got: 1 })
+        );
+        assert_eq!(run("1();", &[]).1, Err(VMErrorKind::NotCallable(ValueType::Number)));
+    }
+}
@@ -0,0 +1,451 @@
+//! Optimization passes over compiled chunks.
+//!
+//! The passes rely on chunks having no jumps: the operands of an instruction
+//! are then always pushed by the instructions right before it.
+
+use crate::bc::{Chunk, Op, SourceSpan, Value};
+use crate::gc::{GcHandle, ObjString, GC};
+
+/// Optimizations that can be turned off, e.g. to read traces more easily.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Fuse common sequences of local variable instructions, see `peephole`.
+    pub superinstructions: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { superinstructions: true }
+    }
+}
+
+/// Runs all passes, see `fold_constants` and `peephole`.
+pub fn optimize(chunk: &mut Chunk, options: Options) {
+    fold_constants(chunk);
+    peephole(chunk, options);
+}
+
+/// An instruction during folding: either from the chunk, or one that pushes
+/// a value computed at compile time and not yet in the constant table.
+enum Instr {
+    Op(Op),
+    Folded(Value),
+}
+
+/// Evaluates operators whose operands are all constants, so that e.g.
+/// `1 + 2 * 3` compiles to a single `Constant` and `!true` to `False`.
+///
+/// Operators that would fail at runtime, like `-"x"` or `1 < nil`, are left
+/// alone so that the error is still raised, on the same line. A folded value
+/// is attributed to the span of its outermost operator. Constants that are no
+/// longer used are dropped from the chunk.
+pub fn fold_constants(chunk: &mut Chunk) {
+    let mut code: Vec<(Instr, SourceSpan)> = Vec::with_capacity(chunk.code.len());
+    let mut allocations = Vec::new();
+    let mut folded_any = false;
+
+    for (&op, &span) in chunk.code.iter().zip(&chunk.debug_info) {
+        let arity = match op {
+            Op::Negate | Op::Not => 1,
+            Op::Add | Op::Subtract | Op::Multiply | Op::Divide => 2,
+            Op::Equal | Op::NotEqual | Op::Greater | Op::GreaterEqual | Op::Less | Op::LessEqual => 2,
+            _ => 0,
+        };
+
+        let operands: Option<Vec<Value>> = code
+            .get(code.len().saturating_sub(arity)..)
+            .filter(|operands| arity > 0 && operands.len() == arity)
+            .and_then(|operands| operands.iter().map(|(instr, _)| constant_value(instr, chunk)).collect());
+
+        let folded = operands.and_then(|operands| match operands[..] {
+            [ref operand] => fold_unary(op, operand),
+            [ref a, ref b] => fold_binary(op, a, b, &mut allocations),
+            _ => None,
+        });
+
+        match folded {
+            Some(value) => {
+                code.truncate(code.len() - arity);
+                let instr = match value.as_bool() {
+                    _ if value.is_nil() => Instr::Op(Op::Nil),
+                    Some(true) => Instr::Op(Op::True),
+                    Some(false) => Instr::Op(Op::False),
+                    None => Instr::Folded(value),
+                };
+                code.push((instr, span));
+                folded_any = true;
+            }
+            None => code.push((Instr::Op(op), span)),
+        }
+    }
+
+    if !folded_any {
+        return;
+    }
+
+    // The constants still in use keep their order; folded values follow them.
+    let mut used = vec![false; chunk.constants.len()];
+    for (instr, _) in &mut code {
+        if let Instr::Op(op) = instr {
+            if let Some(offset) = constant_operand(op) {
+                match used.get_mut(*offset as usize) {
+                    Some(used) => *used = true,
+                    // Not compiled by `lc`; leave it alone.
+                    None => return,
+                }
+            }
+        }
+    }
+
+    let mut remap = vec![0; chunk.constants.len()];
+    let mut constants = Vec::new();
+    for (offset, value) in chunk.constants.iter().enumerate() {
+        if used[offset] {
+            remap[offset] = constants.len();
+            constants.push(*value);
+        }
+    }
+
+    let folded_count = code.iter().filter(|(instr, _)| matches!(instr, Instr::Folded(_))).count();
+    if constants.len() + folded_count > u8::MAX as usize + 1 {
+        // Folding shared string constants can take more room than it frees.
+        return;
+    }
+
+    chunk.code.clear();
+    chunk.debug_info.clear();
+    for (instr, span) in code {
+        let op = match instr {
+            Instr::Op(mut op) => {
+                if let Some(offset) = constant_operand(&mut op) {
+                    *offset = remap[*offset as usize] as u8;
+                }
+                op
+            }
+            Instr::Folded(value) => {
+                constants.push(value);
+                Op::Constant { offset: (constants.len() - 1) as u8 }
+            }
+        };
+        chunk.add_op(op, span);
+    }
+    chunk.constants = constants;
+    chunk.allocations.extend(allocations);
+}
+
+/// Replaces instruction sequences with shorter ones that leave the same stack:
+///
+/// - `Equal, Not` becomes `NotEqual`,
+/// - an instruction that just pushes a value, like `Constant` or `GetLocal`,
+///   followed by `Pop` is removed,
+/// - `SetLocal n, Pop, GetLocal n` becomes `SetLocal n`, since the assigned
+///   value is still on the stack. Likewise for globals.
+///
+/// `Less, Not` is not `GreaterEqual`: `!(nan < 1)` is true, but `nan >= 1` is
+/// false.
+///
+/// With `options.superinstructions`, two `GetLocal`s become `GetLocal2`, and
+/// `i = i + 1;` on a local becomes `AddLocalConst` or `IncrementLocal`.
+///
+/// A new instruction gets the span of the instruction it replaces that can
+/// fail, so runtime errors point at the same place.
+pub fn peephole(chunk: &mut Chunk, options: Options) {
+    let mut code = Vec::with_capacity(chunk.code.len());
+    let mut debug_info = Vec::with_capacity(chunk.debug_info.len());
+
+    for (&op, &span) in chunk.code.iter().zip(&chunk.debug_info) {
+        code.push(op);
+        debug_info.push(span);
+
+        // The code before `op` is rewritten already, so only its end can
+        // match. A rewrite shortens the code and can complete another pattern.
+        while let Some((len, replacement)) = rewrite(&code, options) {
+            let start = code.len() - len;
+            let spans: Vec<SourceSpan> = replacement.iter().map(|&(_, from)| debug_info[start + from]).collect();
+            code.truncate(start);
+            debug_info.truncate(start);
+            code.extend(replacement.into_iter().map(|(op, _)| op));
+            debug_info.extend(spans);
+        }
+    }
+
+    chunk.code = code;
+    chunk.debug_info = debug_info;
+}
+
+/// A rewrite of the end of `code`: how many instructions it replaces, and
+/// the replacements, each with the index of the replaced instruction whose
+/// span it gets.
+fn rewrite(code: &[Op], options: Options) -> Option<(usize, Vec<(Op, usize)>)> {
+    let rewrite = match *code {
+        [.., Op::Equal, Op::Not] => (2, vec![(Op::NotEqual, 0)]),
+        [.., Op::Constant { .. } | Op::Nil | Op::True | Op::False | Op::GetLocal { .. }, Op::Pop] => (2, vec![]),
+        [.., set @ Op::SetLocal { offset: a }, Op::Pop, Op::GetLocal { offset: b }]
+        | [.., set @ Op::SetGlobal { slot: a }, Op::Pop, Op::GetGlobal { slot: b }]
+            if a == b =>
+        {
+            (3, vec![(set, 0)])
+        }
+        _ if !options.superinstructions => return None,
+        [.., Op::GetLocal { offset: first }, Op::GetLocal { offset: second }] => {
+            (2, vec![(Op::GetLocal2 { first, second }, 0)])
+        }
+        [.., Op::GetLocal2 { first, .. }, Op::Pop] => (2, vec![(Op::GetLocal { offset: first }, 0)]),
+        [.., Op::GetLocal { offset: slot }, Op::Constant { offset }, Op::Add] => {
+            (3, vec![(Op::AddLocalConst { slot, offset }, 2)])
+        }
+        // Splits the pair again, for `var b = a; b = b + 1;`.
+        [.., Op::GetLocal2 { first, second }, Op::Constant { offset }, Op::Add] => {
+            (3, vec![(Op::GetLocal { offset: first }, 0), (Op::AddLocalConst { slot: second, offset }, 2)])
+        }
+        [.., Op::AddLocalConst { slot, offset }, Op::SetLocal { offset: target }, Op::Pop] if slot == target => {
+            (3, vec![(Op::IncrementLocal { slot, offset }, 0)])
+        }
+        _ => return None,
+    };
+
+    Some(rewrite)
+}
+
+/// The value an instruction pushes, if it is known at compile time.
+fn constant_value(instr: &Instr, chunk: &Chunk) -> Option<Value> {
+    match instr {
+        Instr::Op(Op::Constant { offset }) => chunk.constants.get(*offset as usize).cloned(),
+        Instr::Op(Op::Nil) => Some(Value::nil()),
+        Instr::Op(Op::True) => Some(Value::from(true)),
+        Instr::Op(Op::False) => Some(Value::from(false)),
+        Instr::Folded(value) => Some(*value),
+        Instr::Op(_) => None,
+    }
+}
+
+/// The constant table offset the instruction refers to, if any.
+fn constant_operand(op: &mut Op) -> Option<&mut u8> {
+    match op {
+        Op::Constant { offset }
+        | Op::AddLocalConst { offset, .. }
+        | Op::IncrementLocal { offset, .. } => Some(offset),
+        _ => None,
+    }
+}
+
+/// The result of `op`, as `VM::run` would compute it, unless that is an error.
+fn fold_unary(op: Op, operand: &Value) -> Option<Value> {
+    match (op, operand.as_num()) {
+        (Op::Negate, Some(num)) => Some(Value::from(-num)),
+        (Op::Not, _) => Some(Value::from(operand.is_falsey())),
+        _ => None,
+    }
+}
+
+/// The result of `op`, as `VM::run` would compute it, unless that is an error.
+/// Concatenated strings are added to `allocations`.
+fn fold_binary(op: Op, a: &Value, b: &Value, allocations: &mut Vec<GcHandle>) -> Option<Value> {
+    match op {
+        Op::Equal => return Some(Value::from(a == b)),
+        Op::NotEqual => return Some(Value::from(a != b)),
+        _ => {}
+    }
+
+    if let (Some(a), Some(b)) = (a.as_num(), b.as_num()) {
+        return match op {
+            Op::Add => Some(Value::from(a + b)),
+            Op::Subtract => Some(Value::from(a - b)),
+            Op::Multiply => Some(Value::from(a * b)),
+            Op::Divide => Some(Value::from(a / b)),
+            Op::Greater => Some(Value::from(a > b)),
+            Op::GreaterEqual => Some(Value::from(a >= b)),
+            Op::Less => Some(Value::from(a < b)),
+            Op::LessEqual => Some(Value::from(a <= b)),
+            _ => None,
+        };
+    }
+
+    match (op, a.as_obj(), b.as_obj()) {
+        (Op::Add, Some(a), Some(b)) => {
+            let (a, b) = (a.downcast::<ObjString>()?, b.downcast::<ObjString>()?);
+            let handle = GC::new_concat_string(a, b);
+            let value = Value::from(handle.get_object());
+            allocations.push(handle);
+            Some(value)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::LinkedList;
+
+    use super::*;
+    use crate::bc::ValueType;
+    use crate::lc;
+    use crate::vm::{VMErrorKind, VM};
+
+    fn compile(source: &str) -> Chunk {
+        let mut chunk = Chunk::new();
+        assert!(lc::compile(source, &mut chunk).is_empty(), "{}", source);
+        chunk
+    }
+
+    fn numbers(code: Vec<Op>, constants: &[f64]) -> Chunk {
+        let lines = vec![1; code.len()];
+        Chunk::new_with(code, lines, constants.iter().copied().map(Value::from).collect(), LinkedList::new())
+    }
+
+    #[test]
+    fn folds_arithmetic_and_comparisons() {
+        use Op::*;
+
+        let cases = [
+            ("print 1 + 2 * 3;", numbers(vec![Constant { offset: 0 }, Print], &[7.])),
+            ("print -(4 - 6) / 4;", numbers(vec![Constant { offset: 0 }, Print], &[0.5])),
+            ("print !true;", numbers(vec![False, Print], &[])),
+            ("print !nil == (1 < 2);", numbers(vec![True, Print], &[])),
+            ("print 1 >= 2;", numbers(vec![False, Print], &[])),
+            ("print 0 / 0 == 0 / 0;", numbers(vec![False, Print], &[])),
+        ];
+
+        for (source, expected) in cases {
+            let chunk = compile(source);
+            assert!(chunk.instr_eq(&expected), "{}\n{:?}", source, chunk);
+            assert_eq!(chunk.debug_info.len(), chunk.code.len());
+        }
+    }
+
+    #[test]
+    fn folds_around_variables() {
+        use Op::*;
+
+        let chunk = compile("{ var a = 2; print a * (3 + 4) - -1; }");
+        let expected = numbers(
+            vec![
+                Constant { offset: 0 },
+                GetLocal { offset: 0 },
+                Constant { offset: 1 },
+                Multiply,
+                Constant { offset: 2 },
+                Subtract,
+                Print,
+                Pop,
+            ],
+            &[2., 7., -1.],
+        );
+
+        assert!(chunk.instr_eq(&expected), "{:?}", chunk);
+    }
+
+    #[test]
+    fn folds_string_concatenation() {
+        let chunk = compile("var s = \"a\" + \"b\" + \"c\"; print s == \"abc\";");
+        let mut output = Vec::new();
+        VM::new().run(&chunk, &mut output).unwrap();
+
+        assert_eq!(chunk.code.len(), 6);
+        assert_eq!(chunk.constants.len(), 2);
+        assert_eq!(String::from_utf8(output).unwrap(), "true\n");
+    }
+
+    #[test]
+    fn peephole_shortens_sequences() {
+        use Op::*;
+
+        let chunk = compile("{ var a; a = 1; print a; a; 2; print !(a == 1); }");
+        let expected = vec![
+            Nil,
+            Constant { offset: 0 },
+            SetLocal { offset: 0 },
+            Print,
+            GetLocal { offset: 0 },
+            Constant { offset: 2 },
+            NotEqual,
+            Print,
+            Pop,
+        ];
+
+        assert_eq!(chunk.code, expected);
+        assert_eq!(chunk.debug_info.len(), expected.len());
+        // `Equal, Not` became `NotEqual` at the span of `==`.
+        assert_eq!(chunk.debug_info[6].column, 42);
+
+        let chunk = compile("var g; g = 2; print g; { var b = 1; var c = 2; }");
+        assert_eq!(
+            chunk.code,
+            vec![Nil, DefineGlobal { slot: 4 }, Constant { offset: 0 }, SetGlobal { slot: 4 }, Print]
+        );
+    }
+
+    #[test]
+    fn superinstructions() {
+        use Op::*;
+
+        let source = "{ var i = 0; var j = i; i = i + 1; j = j + 1; print i + j; print j + 2; }";
+        let mut chunk = Chunk::new();
+        assert!(lc::compile(source, &mut chunk).is_empty());
+        assert_eq!(
+            chunk.code,
+            vec![
+                Constant { offset: 0 },
+                GetLocal { offset: 0 },
+                IncrementLocal { slot: 0, offset: 1 },
+                IncrementLocal { slot: 1, offset: 2 },
+                GetLocal2 { first: 0, second: 1 },
+                Add,
+                Print,
+                AddLocalConst { slot: 1, offset: 3 },
+                Print,
+                Pop,
+                Pop,
+            ]
+        );
+
+        let mut plain = Chunk::new();
+        assert!(lc::compile_with(source, &mut plain, Options { superinstructions: false }).is_empty());
+        assert!(!plain.code.iter().any(|op| matches!(op, GetLocal2 { .. } | AddLocalConst { .. } | IncrementLocal { .. })));
+
+        let mut output = Vec::new();
+        VM::new().run(&chunk, &mut output).unwrap();
+        let mut plain_output = Vec::new();
+        VM::new().run(&plain, &mut plain_output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "2\n3\n");
+        assert_eq!(plain_output, b"2\n3\n");
+    }
+
+    #[test]
+    fn superinstructions_keep_runtime_errors() {
+        let source = "{ var s = \"s\";\n  s = s + 1; }";
+        let mut chunk = Chunk::new();
+        assert!(lc::compile(source, &mut chunk).is_empty());
+        assert!(chunk.code.contains(&Op::IncrementLocal { slot: 0, offset: 1 }));
+
+        let err = VM::new().run(&chunk, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind, VMErrorKind::InvalidAddOperands(ValueType::String, ValueType::Number));
+        let span = err.diagnostic().labels[0].span;
+        assert_eq!((span.line, span.column), (2, 9));
+    }
+
+    #[test]
+    fn not_less_is_not_greater_equal() {
+        let chunk = compile("var nan = 0 / 0; print !(nan < 1); print nan >= 1; print !(nan > 1); print nan <= 1;");
+        let mut output = Vec::new();
+        VM::new().run(&chunk, &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "true\nfalse\ntrue\nfalse\n");
+    }
+
+    #[test]
+    fn keeps_runtime_errors() {
+        let cases = [
+            ("print 1;\nprint -\"x\";", VMErrorKind::InvalidMathOperand(ValueType::String), 2),
+            ("print\n1 + 2 < nil;", VMErrorKind::InvalidMathOperands(ValueType::Number, ValueType::Nil), 2),
+            ("print \"a\" +\n 1 * 2;", VMErrorKind::InvalidAddOperands(ValueType::String, ValueType::Number), 1),
+        ];
+
+        for (source, kind, line) in cases {
+            let chunk = compile(source);
+            let err = VM::new().run(&chunk, &mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind, kind, "{}", source);
+            assert_eq!(err.diagnostic().labels[0].span.line, line, "{}", source);
+        }
+    }
+}
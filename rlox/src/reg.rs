@@ -0,0 +1,83 @@
+//! A register-based alternative to the stack instructions in `bc`.
+//!
+//! Instructions name their operands and destination as registers, the slots
+//! of the current frame: locals keep the slot `resolver` gave them and
+//! temporaries are allocated above them. `print a + b;` with two locals is a
+//! single `Add` and a `Print`, where the stack machine needs four ops.
+//!
+//! See `regcodegen` for the compiler and `regvm` for the interpreter.
+
+use std::collections::LinkedList;
+
+use crate::bc::{Globals, SourceSpan, Value};
+use crate::gc::GcHandle;
+
+pub type Reg = u16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    Constant { dst: Reg, offset: u8 },
+    Nil { dst: Reg },
+    True { dst: Reg },
+    False { dst: Reg },
+    Move { dst: Reg, src: Reg },
+
+    Not { dst: Reg, src: Reg },
+    Negate { dst: Reg, src: Reg },
+    Add { dst: Reg, left: Reg, right: Reg },
+    Subtract { dst: Reg, left: Reg, right: Reg },
+    Multiply { dst: Reg, left: Reg, right: Reg },
+    Divide { dst: Reg, left: Reg, right: Reg },
+    Equal { dst: Reg, left: Reg, right: Reg },
+    NotEqual { dst: Reg, left: Reg, right: Reg },
+    Greater { dst: Reg, left: Reg, right: Reg },
+    GreaterEqual { dst: Reg, left: Reg, right: Reg },
+    Less { dst: Reg, left: Reg, right: Reg },
+    LessEqual { dst: Reg, left: Reg, right: Reg },
+
+    Print { src: Reg },
+
+    /// The global variable instructions take a slot, see `bc::Globals`.
+    DefineGlobal { slot: u8, src: Reg },
+    GetGlobal { dst: Reg, slot: u8 },
+    SetGlobal { slot: u8, src: Reg },
+
+    /// Calls `callee` with the `arg_count` registers after it as arguments.
+    Call { dst: Reg, callee: Reg, arg_count: u8 },
+}
+
+#[derive(Debug)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub debug_info: Vec<SourceSpan>,
+    pub constants: Vec<Value>,
+    pub globals: Globals,
+    pub allocations: LinkedList<GcHandle>,
+    /// Number of registers the code uses.
+    pub frame_size: usize,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chunk {
+    pub fn new() -> Chunk {
+        Chunk {
+            code: Vec::new(),
+            debug_info: Vec::new(),
+            constants: Vec::new(),
+            globals: Globals::default(),
+            allocations: LinkedList::new(),
+            frame_size: 0,
+        }
+    }
+
+    pub fn add_op(&mut self, op: Op, span: impl Into<SourceSpan>) -> &mut Self {
+        self.code.push(op);
+        self.debug_info.push(span.into());
+        self
+    }
+}
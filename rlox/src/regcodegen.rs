@@ -0,0 +1,338 @@
+//! Emits register code for a resolved program, see `reg`.
+
+use std::borrow::Cow;
+
+use crate::ast::{self, BinaryOp, Binding, Expr, Literal, Stmt, UnaryOp};
+use crate::lc::{self, ParseError, ParseErrorKind, Strings, Token};
+use crate::reg::{Chunk, Op, Reg};
+use crate::resolver;
+
+type Result<'src, T> = std::result::Result<T, ParseError<'src>>;
+
+/// Compiles `source` like `codegen::compile`, but to register code.
+pub fn compile<'src>(source: &'src str, chunk: &mut Chunk) -> Vec<ParseError<'src>> {
+    let mut statements = match ast::parse(source) {
+        Ok(statements) => statements,
+        Err(errors) => return errors,
+    };
+
+    let errors = resolver::resolve(&mut statements);
+    if !errors.is_empty() {
+        return errors;
+    }
+
+    generate(&statements, chunk)
+}
+
+/// Appends the code for `statements`, which must have been resolved.
+pub fn generate<'src>(statements: &[Stmt<'src>], chunk: &mut Chunk) -> Vec<ParseError<'src>> {
+    let mut generator = Generator {
+        chunk,
+        strings: Strings::default(),
+        locals: 0,
+        next_free: 0,
+        errors: Vec::new(),
+    };
+
+    generator.statements(statements);
+    generator.errors
+}
+
+/// The register of the local `expr` reads, if it is a plain local variable.
+fn local_slot(expr: &Expr) -> Option<Reg> {
+    match expr {
+        Expr::Variable { binding: Binding::Local(slot), .. } => Some(*slot as Reg),
+        Expr::Grouping(inner) => local_slot(inner),
+        _ => None,
+    }
+}
+
+/// Whether evaluating `expr` may assign to a local.
+fn assigns_local(expr: &Expr) -> bool {
+    match expr {
+        Expr::Assign { binding: Binding::Local(_), .. } => true,
+        Expr::Assign { value, .. } => assigns_local(value),
+        Expr::Literal { .. } | Expr::Variable { .. } => false,
+        Expr::Grouping(inner) | Expr::Unary { operand: inner, .. } => assigns_local(inner),
+        Expr::Binary { left, right, .. } => assigns_local(left) || assigns_local(right),
+        Expr::Call { callee, arguments, .. } => assigns_local(callee) || arguments.iter().any(assigns_local),
+    }
+}
+
+struct Generator<'src, 'c> {
+    chunk: &'c mut Chunk,
+    strings: Strings<'src>,
+    /// Locals in scope, in the registers below this one.
+    locals: usize,
+    /// First register not in use by a local or a temporary.
+    next_free: usize,
+    errors: Vec<ParseError<'src>>,
+}
+
+impl<'src> Generator<'src, '_> {
+    fn add_string(&mut self, string: impl Into<Cow<'src, str>>, location: &Token<'src>) -> Result<'src, u8> {
+        self.strings
+            .intern(string, &mut self.chunk.constants, &mut self.chunk.allocations, location)
+    }
+
+    /// Allocates a register above the locals and the temporaries in use.
+    fn temporary(&mut self, location: &Token<'src>) -> Result<'src, Reg> {
+        let reg = Reg::try_from(self.next_free)
+            .map_err(|_| ParseError::at(location.clone(), ParseErrorKind::TooManyRegisters))?;
+        self.next_free += 1;
+        self.chunk.frame_size = self.chunk.frame_size.max(self.next_free);
+        Ok(reg)
+    }
+
+    /// Evaluates `expr` into a new temporary, freeing the ones it used on the way.
+    fn temporary_with(&mut self, expr: &Expr<'src>, location: &Token<'src>) -> Result<'src, Reg> {
+        let reg = self.temporary(location)?;
+        self.expression(expr, reg)?;
+        self.next_free = reg as usize + 1;
+        Ok(reg)
+    }
+
+    /// A register holding the value of `expr`. Locals are read in place.
+    fn operand(&mut self, expr: &Expr<'src>, location: &Token<'src>) -> Result<'src, Reg> {
+        match local_slot(expr) {
+            Some(slot) => Ok(slot),
+            None => self.temporary_with(expr, location),
+        }
+    }
+
+    /// Like `lc::Parser::declaration`, an error ends its statement but not the ones after it.
+    fn statements(&mut self, statements: &[Stmt<'src>]) {
+        for stmt in statements {
+            if let Err(err) = self.statement(stmt) {
+                self.errors.push(err);
+            }
+            self.next_free = self.locals;
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt<'src>) -> Result<'src, ()> {
+        match stmt {
+            Stmt::Expression { expression, semicolon } => match expression {
+                Expr::Assign { value, binding: Binding::Local(slot), .. } => self.expression(value, *slot as Reg)?,
+                _ => {
+                    self.temporary_with(expression, semicolon)?;
+                }
+            },
+            Stmt::Print { keyword, expression } => {
+                let src = self.operand(expression, keyword)?;
+                self.chunk.add_op(Op::Print { src }, keyword.source_span());
+            }
+            Stmt::Var { keyword, name, initializer, binding } => match *binding {
+                Binding::Global => {
+                    let slot = lc::global_slot(&mut self.chunk.globals, name)?;
+                    let src = match initializer {
+                        Some(initializer) => self.operand(initializer, keyword)?,
+                        None => {
+                            let dst = self.temporary(name)?;
+                            self.chunk.add_op(Op::Nil { dst }, name.source_span());
+                            dst
+                        }
+                    };
+                    self.chunk.add_op(Op::DefineGlobal { slot, src }, keyword.source_span());
+                }
+                Binding::Local(slot) => {
+                    // The initializer can't read the local, so it may write it directly.
+                    self.locals = slot as usize + 1;
+                    self.next_free = self.locals;
+                    self.chunk.frame_size = self.chunk.frame_size.max(self.next_free);
+
+                    let dst = slot as Reg;
+                    match initializer {
+                        Some(initializer) => self.expression(initializer, dst)?,
+                        None => {
+                            self.chunk.add_op(Op::Nil { dst }, name.source_span());
+                        }
+                    }
+                }
+            },
+            Stmt::Block { statements, .. } => {
+                let locals = self.locals;
+                self.statements(statements);
+                self.locals = locals;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emits code that leaves the value of `expr` in `dst`. `dst` is written
+    /// only once the operands are read, so `expr` may read its old value.
+    fn expression(&mut self, expr: &Expr<'src>, dst: Reg) -> Result<'src, ()> {
+        match expr {
+            Expr::Literal { value, token } => match value {
+                Literal::Nil => {
+                    self.chunk.add_op(Op::Nil { dst }, token.source_span());
+                }
+                Literal::Bool(true) => {
+                    self.chunk.add_op(Op::True { dst }, token.source_span());
+                }
+                Literal::Bool(false) => {
+                    self.chunk.add_op(Op::False { dst }, token.source_span());
+                }
+                Literal::Number(value) => {
+                    let offset = lc::next_constant(&self.chunk.constants, token)?;
+                    self.chunk.constants.push((*value).into());
+                    self.chunk.add_op(Op::Constant { dst, offset }, token.source_span());
+                }
+                Literal::String(string) => {
+                    let offset = self.add_string(string.clone(), token)?;
+                    self.chunk.add_op(Op::Constant { dst, offset }, token.source_span());
+                }
+            },
+            Expr::Grouping(inner) => self.expression(inner, dst)?,
+            Expr::Unary { op, token, operand } => {
+                let src = self.operand(operand, token)?;
+                let op = match op {
+                    UnaryOp::Negate => Op::Negate { dst, src },
+                    UnaryOp::Not => Op::Not { dst, src },
+                };
+                self.chunk.add_op(op, token.source_span());
+            }
+            Expr::Binary { op, token, left, right } => {
+                // A local read in place would see assignments made by `right`.
+                let left = match assigns_local(right) {
+                    true => self.temporary_with(left, token)?,
+                    false => self.operand(left, token)?,
+                };
+                let right = self.operand(right, token)?;
+
+                let op = match op {
+                    BinaryOp::Add => Op::Add { dst, left, right },
+                    BinaryOp::Subtract => Op::Subtract { dst, left, right },
+                    BinaryOp::Multiply => Op::Multiply { dst, left, right },
+                    BinaryOp::Divide => Op::Divide { dst, left, right },
+                    BinaryOp::Equal => Op::Equal { dst, left, right },
+                    BinaryOp::NotEqual => Op::NotEqual { dst, left, right },
+                    BinaryOp::Greater => Op::Greater { dst, left, right },
+                    BinaryOp::GreaterEqual => Op::GreaterEqual { dst, left, right },
+                    BinaryOp::Less => Op::Less { dst, left, right },
+                    BinaryOp::LessEqual => Op::LessEqual { dst, left, right },
+                };
+                self.chunk.add_op(op, token.source_span());
+            }
+            Expr::Variable { name, binding } => {
+                let op = match *binding {
+                    Binding::Local(slot) if slot as Reg == dst => return Ok(()),
+                    Binding::Local(slot) => Op::Move { dst, src: slot as Reg },
+                    Binding::Global => Op::GetGlobal { dst, slot: lc::global_slot(&mut self.chunk.globals, name)? },
+                };
+                self.chunk.add_op(op, name.source_span());
+            }
+            Expr::Assign { name, value, binding } => match *binding {
+                Binding::Local(slot) => {
+                    let slot = slot as Reg;
+                    self.expression(value, slot)?;
+                    if slot != dst {
+                        self.chunk.add_op(Op::Move { dst, src: slot }, name.source_span());
+                    }
+                }
+                Binding::Global => {
+                    let slot = lc::global_slot(&mut self.chunk.globals, name)?;
+                    self.expression(value, dst)?;
+                    self.chunk.add_op(Op::SetGlobal { slot, src: dst }, name.source_span());
+                }
+            },
+            Expr::Call { callee, paren, arguments } => {
+                // The arguments go in the registers right after the callee.
+                let callee = self.temporary_with(callee, paren)?;
+                for argument in arguments {
+                    self.temporary_with(argument, paren)?;
+                }
+                // The parser rejects calls with more than 255 arguments.
+                let arg_count = arguments.len() as u8;
+                self.chunk.add_op(Op::Call { dst, callee, arg_count }, paren.source_span());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bc;
+    use crate::lc;
+    use crate::vm::{Budget, VMError, VMErrorKind, VM};
+
+    /// Output and outcome of running `source` on the stack VM, or on the register VM.
+    fn run(source: &str, registers: bool) -> (String, std::result::Result<(), VMError>) {
+        let mut vm = VM::new();
+        vm.set_script_args(vec!["arg".to_string()]);
+        let mut output = Vec::new();
+
+        let result = if registers {
+            let mut chunk = Chunk::new();
+            assert!(compile(source, &mut chunk).is_empty(), "{}", source);
+            vm.run_registers(&chunk, &mut output)
+        } else {
+            let mut chunk = bc::Chunk::new();
+            assert!(lc::compile(source, &mut chunk).is_empty(), "{}", source);
+            vm.run(&chunk, &mut output)
+        };
+        (String::from_utf8(output).unwrap(), result)
+    }
+
+    #[test]
+    fn same_behaviour_as_stack_vm() {
+        let sources = [
+            "print 1 + 2 * -3 / (4 - 5); print !true == false != nil; print 1 <= 2; print 1 > 2;",
+            "var a; var b = \"b\"; a = b = \"a\"; print a + b + \"b\"; print a == b;",
+            "var a = 1; { var b = a + 1; { var c = b; b = c * 2; print b; } print b; } print a;",
+            "{ var a = 1; print a + (a = 2); print (a = 3) + a; print a; var b = a = a + 1; print b; }",
+            "{ var a = 1; var b = 2; a = b = a + b; print a; print b; a; -a; b = -b; print a - b; }",
+            "print argc(); { var n = 0; print argv(n); print argv(n + 1); }",
+            "{ var a = 1; var b = \"s\"; print a; print a - b; }",
+            "print 1; print x; print 2;",
+            "var g = 1; g = g + 1; { var l = g; print l = nope = 3; }",
+            "{ var f = argv; print f(0, 1); }",
+            "print argv(\"0\");",
+            "{ var a = 3; exit(a); print a; }",
+        ];
+
+        for source in sources {
+            assert_eq!(run(source, true), run(source, false), "{}", source);
+        }
+    }
+
+    #[test]
+    fn budget_stops_register_runs() {
+        let mut chunk = Chunk::new();
+        assert!(compile("print 1; { var a = 2; print a; } print 3;", &mut chunk).is_empty());
+        let mut vm = VM::new();
+
+        vm.set_instruction_budget(Some(3));
+        let mut output = Vec::new();
+        let err = vm.run_registers(&chunk, &mut output).unwrap_err();
+        assert_eq!(err.kind, VMErrorKind::BudgetExhausted(Budget::Instructions(3)));
+        assert_eq!(output, b"1\n");
+
+        vm.set_instruction_budget(None);
+        let mut output = Vec::new();
+        vm.run_registers(&chunk, &mut output).unwrap();
+        assert_eq!(output, b"1\n2\n3\n");
+    }
+
+    #[test]
+    fn reads_locals_in_place() {
+        let mut chunk = Chunk::new();
+        assert!(compile("{ var a = 1; var b = 2; print a + b; a = a + b; }", &mut chunk).is_empty());
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::Constant { dst: 0, offset: 0 },
+                Op::Constant { dst: 1, offset: 1 },
+                Op::Add { dst: 2, left: 0, right: 1 },
+                Op::Print { src: 2 },
+                Op::Add { dst: 0, left: 0, right: 1 },
+            ]
+        );
+        assert_eq!(chunk.frame_size, 3);
+    }
+}
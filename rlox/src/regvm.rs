@@ -0,0 +1,219 @@
+//! Runs the register code in `reg` on a `VM`, whose stack is the register
+//! file. Natives, globals and runtime errors are shared with `VM::run`.
+
+use std::io;
+use std::ops::ControlFlow;
+
+use crate::bc::Value;
+use crate::gc::ObjNative;
+use crate::reg::{Chunk, Op, Reg};
+use crate::vm::{CallFrame, VMError, VMErrorKind, VM};
+
+type Result<T> = std::result::Result<T, VMError>;
+
+impl VM {
+    pub fn stdrun_registers(&mut self, chunk: &Chunk) -> Result<()> {
+        self.run_registers(chunk, &mut io::stdout())
+    }
+
+    pub fn run_registers<Output: io::Write>(&mut self, chunk: &Chunk, output: &mut Output) -> Result<()> {
+        self.reset_globals(&chunk.globals);
+        self.start();
+        self.stack.resize(chunk.frame_size, Value::nil());
+
+        let result = if self.trace {
+            self.run_registers_traced(chunk, output)
+        } else {
+            self.dispatch_registers(chunk, output)
+        };
+        result.map_err(|err| self.locate(err, &chunk.debug_info))
+    }
+
+    /// Like `VM::dispatch`, spans are only looked up by calls and errors.
+    fn dispatch_registers<Output: io::Write>(&mut self, chunk: &Chunk, output: &mut Output) -> Result<()> {
+        let code = chunk.code.as_slice();
+        while let Some(&instr) = code.get(self.pc) {
+            self.pc += 1;
+            self.tick()?;
+            if self.execute_register(instr, chunk, output)?.is_break() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `dispatch_registers`, printing the registers and each instruction before it runs.
+    fn run_registers_traced<Output: io::Write>(&mut self, chunk: &Chunk, output: &mut Output) -> Result<()> {
+        while let Some(&instr) = chunk.code.get(self.pc) {
+            print!("            [ ");
+            for value in self.stack.iter() {
+                print!("{:?} | ", value);
+            }
+            println!("]\n");
+            println!("{:04} {:?}\n", self.pc, instr);
+
+            self.pc += 1;
+            self.tick()?;
+            if self.execute_register(instr, chunk, output)?.is_break() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs `instr`, the instruction before `self.pc`. Breaks once the script
+    /// has asked to exit.
+    #[inline(always)]
+    fn execute_register<Output: io::Write>(
+        &mut self,
+        instr: Op,
+        chunk: &Chunk,
+        output: &mut Output,
+    ) -> Result<ControlFlow<()>> {
+        match instr {
+            Op::Constant { dst, offset } => {
+                let value = *self.constant(&chunk.constants, offset)?;
+                self.set_register(dst, value)?
+            }
+            Op::Nil { dst } => self.set_register(dst, Value::nil())?,
+            Op::True { dst } => self.set_register(dst, Value::from(true))?,
+            Op::False { dst } => self.set_register(dst, Value::from(false))?,
+            Op::Move { dst, src } => {
+                let value = *self.register(src)?;
+                self.set_register(dst, value)?
+            }
+            Op::Not { dst, src } => {
+                let value = self.register(src)?.is_falsey();
+                self.set_register(dst, value.into())?
+            }
+            Op::Negate { dst, src } => {
+                let value = self.register(src)?;
+                let num = value
+                    .as_num()
+                    .ok_or_else(|| self.err(VMErrorKind::InvalidMathOperand(value.value_type())))?;
+                self.set_register(dst, (-num).into())?
+            }
+            Op::Add { dst, left, right } => {
+                let (a, b) = (*self.register(left)?, *self.register(right)?);
+                let sum = self.add(&a, &b)?;
+                self.set_register(dst, sum)?
+            }
+            Op::Subtract { dst, left, right }
+            | Op::Multiply { dst, left, right }
+            | Op::Divide { dst, left, right } => {
+                let (a, b) = self.numbers(left, right)?;
+                let r = match instr {
+                    Op::Subtract { .. } => a - b,
+                    Op::Multiply { .. } => a * b,
+                    Op::Divide { .. } => a / b,
+                    _ => unreachable!(),
+                };
+                self.set_register(dst, r.into())?
+            }
+            Op::Greater { dst, left, right }
+            | Op::GreaterEqual { dst, left, right }
+            | Op::Less { dst, left, right }
+            | Op::LessEqual { dst, left, right } => {
+                let (a, b) = self.numbers(left, right)?;
+                let r = match instr {
+                    Op::Greater { .. } => a > b,
+                    Op::GreaterEqual { .. } => a >= b,
+                    Op::Less { .. } => a < b,
+                    Op::LessEqual { .. } => a <= b,
+                    _ => unreachable!(),
+                };
+                self.set_register(dst, r.into())?
+            }
+            Op::Equal { dst, left, right } | Op::NotEqual { dst, left, right } => {
+                let equal = self.register(left)? == self.register(right)?;
+                let r = equal == matches!(instr, Op::Equal { .. });
+                self.set_register(dst, r.into())?
+            }
+            Op::Print { src } => writeln!(output, "{}", self.register(src)?).unwrap(),
+            Op::DefineGlobal { slot, src } => {
+                let index = self.global_slot(slot)?;
+                self.globals[index] = Some(*self.register(src)?);
+            }
+            Op::GetGlobal { dst, slot } => {
+                let index = self.global_slot(slot)?;
+                match self.globals[index] {
+                    Some(value) => self.set_register(dst, value)?,
+                    None => return Err(self.undefined_variable(&chunk.globals, slot)),
+                }
+            }
+            Op::SetGlobal { slot, src } => {
+                let index = self.global_slot(slot)?;
+                if self.globals[index].is_none() {
+                    return Err(self.undefined_variable(&chunk.globals, slot));
+                }
+                self.globals[index] = Some(*self.register(src)?);
+            }
+            Op::Call { dst, callee, arg_count } => {
+                // Natives see the call in the stack trace.
+                self.span = chunk.debug_info.get(self.pc - 1).copied().unwrap_or_default();
+                let callee_value = self.register(callee)?;
+                let native = callee_value
+                    .as_obj()
+                    .and_then(|object| object.downcast::<ObjNative>())
+                    .ok_or_else(|| self.err(VMErrorKind::NotCallable(callee_value.value_type())))?;
+
+                if native.arity() != arg_count {
+                    return Err(self.err(VMErrorKind::ArityMismatch {
+                        expected: native.arity(),
+                        got: arg_count,
+                    }));
+                }
+
+                let args = self.registers(callee as usize + 1, arg_count)?;
+                if let Some(caller) = self.frames.last_mut() {
+                    caller.span = self.span;
+                }
+                self.frames.push(CallFrame { function: Some(native.name()), span: self.span });
+                let result = native.function()(self, &args);
+                self.frames.pop();
+                self.set_register(dst, result?)?;
+
+                if self.exit_code().is_some() {
+                    return Ok(ControlFlow::Break(()));
+                }
+            }
+        }
+
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn register(&self, reg: Reg) -> Result<&Value> {
+        self.stack
+            .get(reg as usize)
+            .ok_or_else(|| self.err(VMErrorKind::InvalidBytecode("register out of range")))
+    }
+
+    fn set_register(&mut self, reg: Reg, value: Value) -> Result<()> {
+        match self.stack.get_mut(reg as usize) {
+            Some(register) => {
+                *register = value;
+                Ok(())
+            }
+            None => Err(self.err(VMErrorKind::InvalidBytecode("register out of range"))),
+        }
+    }
+
+    /// The arguments of a call, `count` registers from `first` on.
+    fn registers(&self, first: usize, count: u8) -> Result<Vec<Value>> {
+        self.stack
+            .get(first..first + count as usize)
+            .map(<[Value]>::to_vec)
+            .ok_or_else(|| self.err(VMErrorKind::InvalidBytecode("register out of range")))
+    }
+
+    /// The operands of an arithmetic or comparison instruction.
+    fn numbers(&self, left: Reg, right: Reg) -> Result<(f64, f64)> {
+        let (a, b) = (self.register(left)?, self.register(right)?);
+        match (a.as_num(), b.as_num()) {
+            (Some(a), Some(b)) => Ok((a, b)),
+            _ => Err(self.err(VMErrorKind::InvalidMathOperands(a.value_type(), b.value_type()))),
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Binds the variables of a parsed program to stack slots, see `ast`.
+
+use crate::ast::{Binding, Expr, Stmt};
+use crate::bc::SourceSpan;
+use crate::lc::{ParseError, ParseErrorKind, Token};
+
+type Result<'src, T> = std::result::Result<T, ParseError<'src>>;
+
+/// Rebinds every variable declared in a block, and every use of one, to its
+/// stack slot. Reports the same scope errors as `lc::compile`.
+pub fn resolve<'src>(statements: &mut [Stmt<'src>]) -> Vec<ParseError<'src>> {
+    let mut resolver = Resolver {
+        scopes: Scopes::default(),
+        errors: Vec::new(),
+    };
+
+    resolver.statements(statements);
+    resolver.errors
+}
+
+struct Local<'src> {
+    name: &'src str,
+    depth: usize,
+    initialized: bool,
+    span: SourceSpan,
+}
+
+/// The locals in scope and the rules for declaring and using them, shared
+/// by `Resolver` and the single-pass `lc::compile`.
+#[derive(Default)]
+pub(crate) struct Scopes<'src> {
+    /// Locals in scope, indexed by slot.
+    locals: Vec<Local<'src>>,
+    depth: usize,
+}
+
+impl<'src> Scopes<'src> {
+    pub(crate) fn in_global_scope(&self) -> bool {
+        self.depth == 0
+    }
+
+    pub(crate) fn enter(&mut self) {
+        self.depth += 1;
+    }
+
+    /// Leaves the innermost scope and returns how many locals it had.
+    pub(crate) fn exit(&mut self) -> usize {
+        let mut pop_count = 0;
+        while self.locals.last().is_some_and(|local| local.depth == self.depth) {
+            self.locals.pop();
+            pop_count += 1;
+        }
+        self.depth -= 1;
+        pop_count
+    }
+
+    /// Declares `name` in the innermost scope and returns its slot. It can't
+    /// be read until `mark_last_initialized`.
+    pub(crate) fn declare(&mut self, name: &Token<'src>) -> Result<'src, u8> {
+        let Ok(slot) = u8::try_from(self.locals.len()) else {
+            return Err(ParseError::at(name.clone(), ParseErrorKind::TooManyLocals));
+        };
+
+        let mut in_scope = self.locals.iter().rev().take_while(|local| local.depth == self.depth);
+        if let Some(previous) = in_scope.find(|local| local.name == name.span) {
+            return Err(ParseError::at(name.clone(), ParseErrorKind::DuplicateLocalInScope)
+                .with_label(previous.span, "previous declaration here"));
+        }
+
+        self.locals.push(Local {
+            name: name.span,
+            depth: self.depth,
+            initialized: false,
+            span: name.source_span(),
+        });
+        Ok(slot)
+    }
+
+    pub(crate) fn mark_last_initialized(&mut self) {
+        if let Some(local) = self.locals.last_mut() {
+            local.initialized = true;
+        }
+    }
+
+    /// What a use of `name` refers to.
+    pub(crate) fn resolve(&self, name: &Token<'src>) -> Result<'src, Binding> {
+        match self.locals.iter().rposition(|local| local.name == name.span) {
+            Some(slot) if !self.locals[slot].initialized => {
+                Err(ParseError::at(name.clone(), ParseErrorKind::LocalInOwnInitializer))
+            }
+            Some(slot) => Ok(Binding::Local(slot as u8)),
+            None => Ok(Binding::Global),
+        }
+    }
+}
+
+struct Resolver<'src> {
+    scopes: Scopes<'src>,
+    errors: Vec<ParseError<'src>>,
+}
+
+impl<'src> Resolver<'src> {
+    /// Like `lc::Parser::declaration`, an error ends its statement but not the ones after it.
+    fn statements(&mut self, statements: &mut [Stmt<'src>]) {
+        for stmt in statements {
+            if let Err(err) = self.statement(stmt) {
+                self.errors.push(err);
+            }
+        }
+    }
+
+    fn statement(&mut self, stmt: &mut Stmt<'src>) -> Result<'src, ()> {
+        match stmt {
+            Stmt::Expression { expression, .. } | Stmt::Print { expression, .. } => self.expression(expression),
+            Stmt::Var { name, initializer, binding, .. } => {
+                if !self.scopes.in_global_scope() {
+                    *binding = Binding::Local(self.scopes.declare(name)?);
+                }
+
+                if let Some(initializer) = initializer {
+                    self.expression(initializer)?;
+                }
+
+                if let Binding::Local(_) = *binding {
+                    self.scopes.mark_last_initialized();
+                }
+                Ok(())
+            }
+            Stmt::Block { statements, .. } => {
+                self.scopes.enter();
+                self.statements(statements);
+                self.scopes.exit();
+                Ok(())
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &mut Expr<'src>) -> Result<'src, ()> {
+        match expr {
+            Expr::Literal { .. } => Ok(()),
+            Expr::Grouping(inner) => self.expression(inner),
+            Expr::Unary { operand, .. } => self.expression(operand),
+            Expr::Binary { left, right, .. } => {
+                self.expression(left)?;
+                self.expression(right)
+            }
+            Expr::Variable { name, binding } => {
+                *binding = self.scopes.resolve(name)?;
+                Ok(())
+            }
+            Expr::Assign { name, value, binding } => {
+                *binding = self.scopes.resolve(name)?;
+                self.expression(value)
+            }
+            Expr::Call { callee, arguments, .. } => {
+                self.expression(callee)?;
+                arguments.iter_mut().try_for_each(|argument| self.expression(argument))
+            }
+        }
+    }
+}
@@ -1,66 +1,226 @@
-use crate::bc::{Chunk, Op, TraceInfo, Value};
-use crate::gc::{GcHandle, ObjString, ObjectType, GC};
-use std::collections::{hash_map, HashMap, LinkedList};
+use crate::bc::{Chunk, Globals, Op, SourceSpan, TraceInfo, Value, ValueType};
+use crate::diagnostics::{Diagnostic, Stage};
+use crate::gc::{GcHandle, ObjNative, ObjectType, GC};
+use crate::natives::NATIVES;
+use std::collections::LinkedList;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value>;
+
 pub struct VM {
     pub trace: bool,
-    stack: Vec<Value>,
-    pc: usize,
-    line: usize,
+    /// The value stack; `regvm` uses it as the register file.
+    pub(crate) stack: Vec<Value>,
+    pub(crate) pc: usize,
+    pub(crate) span: SourceSpan,
+    natives: Vec<(GcHandle, GcHandle)>,
+    /// Global variables, indexed by slot. `None` until the variable is defined.
+    pub(crate) globals: Vec<Option<Value>>,
+    allocations: LinkedList<GcHandle>,
+    script_args: Vec<String>,
+    exit_code: Option<u8>,
+    pub(crate) frames: Vec<CallFrame>,
+    /// Instructions executed by the current or last run.
+    pub(crate) instructions: u64,
+    instruction_budget: Option<u64>,
+    timeout: Option<Duration>,
+    /// When the current run times out, if it has a timeout.
+    deadline: Option<Instant>,
+    /// Value of `instructions` at which `check_limits` runs next.
+    next_check: u64,
+}
+
+/// Instructions between two looks at the clock when a run has a timeout.
+const CLOCK_CHECK_INTERVAL: u64 = 4096;
+
+/// A limit on a single run, see `VM::set_instruction_budget` and `VM::set_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Instructions(u64),
+    Time(Duration),
+}
+
+/// An active call; `function` is `None` for the top-level script.
+pub(crate) struct CallFrame {
+    pub(crate) function: Option<&'static str>,
+    pub(crate) span: SourceSpan,
+}
+
+/// One entry of the call stack captured when a runtime error occurs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub span: SourceSpan,
+    pub function: Option<String>,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.span.line, name),
+            None => write!(f, "[line {}] in script", self.span.line),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct VMError {
-    line: usize,
-    kind: VMErrorKind,
-    msg: Option<String>,
+    pub kind: VMErrorKind,
+    /// Call stack at the point of failure, innermost frame first.
+    pub trace: Vec<StackFrame>,
 }
 
 impl VMError {
-    fn new(kind: VMErrorKind, line: usize) -> Self {
-        VMError { line, kind, msg: None }
+    fn new(kind: VMErrorKind, trace: Vec<StackFrame>) -> Self {
+        VMError { kind, trace }
+    }
+
+    /// The error at the innermost frame, with every caller labelled as a call site.
+    pub fn diagnostic(&self) -> Diagnostic {
+        let mut frames = self.trace.iter();
+        let innermost = frames.next().map(|frame| frame.span).unwrap_or_default();
+
+        frames.fold(
+            Diagnostic::new(Stage::Runtime, self.kind.code(), self.kind.detailed(), innermost),
+            |diagnostic, frame| diagnostic.with_label(frame.span, "called from here"),
+        )
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum VMErrorKind {
-    InvalidAddOperands,
-    InvalidMathOperands,
-    InvalidMathOperand,
-    UndefinedVariable,
+    /// Operand types of a `+` that is neither numeric nor string concatenation.
+    InvalidAddOperands(ValueType, ValueType),
+    InvalidMathOperands(ValueType, ValueType),
+    InvalidMathOperand(ValueType),
+    UndefinedVariable(String),
     PopFromEmptyStack,
+    NotCallable(ValueType),
+    ArityMismatch { expected: u8, got: u8 },
+    ExpectedStringArgument(ValueType),
+    ExpectedIntegerArgument,
+    InvalidExitCode,
+    /// The chunk was not produced by the compiler; says which operand is out of place.
+    InvalidBytecode(&'static str),
+    /// The run was stopped before it finished.
+    BudgetExhausted(Budget),
+}
+
+impl VMErrorKind {
+    /// Stable error code, see `diagnostics::Diagnostic`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            VMErrorKind::InvalidAddOperands(..) => "E0200",
+            VMErrorKind::InvalidMathOperands(..) => "E0201",
+            VMErrorKind::InvalidMathOperand(_) => "E0202",
+            VMErrorKind::UndefinedVariable(_) => "E0203",
+            VMErrorKind::PopFromEmptyStack => "E0204",
+            VMErrorKind::NotCallable(_) => "E0205",
+            VMErrorKind::ArityMismatch { .. } => "E0206",
+            VMErrorKind::ExpectedStringArgument(_) => "E0207",
+            VMErrorKind::ExpectedIntegerArgument => "E0208",
+            VMErrorKind::InvalidExitCode => "E0209",
+            VMErrorKind::InvalidBytecode(_) => "E0210",
+            VMErrorKind::BudgetExhausted(_) => "E0211",
+        }
+    }
+}
+
+impl VMErrorKind {
+    /// The message, naming the offending types where it has them. The
+    /// `Display` form is the reference implementation's message without them.
+    pub fn detailed(&self) -> String {
+        let types = match self {
+            VMErrorKind::InvalidAddOperands(a, b) | VMErrorKind::InvalidMathOperands(a, b) =>
+                format!("{} and {}", a, b),
+            VMErrorKind::InvalidMathOperand(a)
+            | VMErrorKind::NotCallable(a)
+            | VMErrorKind::ExpectedStringArgument(a) => a.to_string(),
+            _ => return self.to_string(),
+        };
+
+        let message = self.to_string();
+        format!("{}, got {}.", message.strip_suffix('.').unwrap_or(&message), types)
+    }
+}
+
+impl fmt::Display for VMErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VMErrorKind::InvalidAddOperands(..) =>
+                write!(f, "Operands must be two numbers or two strings."),
+            VMErrorKind::InvalidMathOperands(..) =>
+                write!(f, "Operands must be numbers."),
+            VMErrorKind::InvalidMathOperand(_) =>
+                write!(f, "Operand must be a number."),
+            VMErrorKind::UndefinedVariable(name) =>
+                write!(f, "Undefined variable '{}'.", name),
+            VMErrorKind::PopFromEmptyStack =>
+                write!(f, "Stack underflow."),
+            VMErrorKind::NotCallable(_) =>
+                write!(f, "Can only call functions and classes."),
+            VMErrorKind::ArityMismatch { expected, got } =>
+                write!(f, "Expected {} arguments but got {}.", expected, got),
+            VMErrorKind::ExpectedStringArgument(_) =>
+                write!(f, "Argument must be a string."),
+            VMErrorKind::ExpectedIntegerArgument =>
+                write!(f, "Argument must be an integer."),
+            VMErrorKind::InvalidExitCode =>
+                write!(f, "Exit code must be between 0 and 255."),
+            VMErrorKind::InvalidBytecode(problem) =>
+                write!(f, "Invalid bytecode: {}.", problem),
+            VMErrorKind::BudgetExhausted(Budget::Instructions(budget)) =>
+                write!(f, "Execution stopped after {} instructions.", budget),
+            VMErrorKind::BudgetExhausted(Budget::Time(timeout)) =>
+                write!(f, "Execution stopped after {:?}.", timeout),
+        }
+    }
 }
 
 impl fmt::Display for VMError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.kind {
-            VMErrorKind::InvalidAddOperands =>
-                {write!(f, "Operands must be two numbers or two strings.")?; }
-            VMErrorKind::InvalidMathOperands =>
-                {write!(f, "Operands must be numbers.")?; }
-            VMErrorKind::InvalidMathOperand =>
-                {write!(f, "Operand must be a number.")?; }
-            _ => {}
-        };
+        write!(f, "{}", self.kind)?;
 
-        if let Some(msg) = &self.msg {
-            write!(f, "{}", msg)?
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?
         }
 
-        write!(f, "\n[line {}]", self.line)
+        Ok(())
     }
 }
 
 type Result<T> = std::result::Result<T, VMError>;
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> VM {
         VM {
             trace: false,
             stack: Vec::new(),
             pc: 0,
-            line: 0,
+            span: SourceSpan::default(),
+            natives: NATIVES
+                .iter()
+                .map(|&(name, arity, function)| {
+                    (GC::new_string(name), GC::new_native(name, arity, function))
+                })
+                .collect(),
+            globals: Vec::new(),
+            allocations: LinkedList::new(),
+            script_args: Vec::new(),
+            exit_code: None,
+            frames: Vec::new(),
+            instructions: 0,
+            instruction_budget: None,
+            timeout: None,
+            deadline: None,
+            next_check: 0,
         }
     }
 
@@ -68,28 +228,121 @@ impl VM {
         self.trace = trace;
     }
 
+    pub fn set_script_args(&mut self, args: Vec<String>) {
+        self.script_args = args;
+    }
+
+    pub fn script_args(&self) -> &[String] {
+        &self.script_args
+    }
+
+    /// Stops every later run after it executed `budget` instructions.
+    pub fn set_instruction_budget(&mut self, budget: Option<u64>) {
+        self.instruction_budget = budget;
+    }
+
+    /// Stops every later run once it took `timeout`. The clock is only read
+    /// every few thousand instructions, so a run may overshoot slightly.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Exit status requested by the script through the `exit` native, if any.
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit_code
+    }
+
+    /// Number of instructions the last run executed, including a failing one.
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions
+    }
+
+    pub(crate) fn halt(&mut self, code: u8) {
+        self.exit_code = Some(code);
+    }
+
+    pub(crate) fn new_string(&mut self, content: &str) -> Value {
+        let handle = GC::new_string(content);
+        let value = Value::from(handle.get_object());
+        self.allocations.push_front(handle);
+        value
+    }
+
     fn push(&mut self, value: Value) {
         self.stack.push(value);
     }
 
-    fn err(&mut self, kind: VMErrorKind) -> VMError {
-        VMError::new(
-            kind,
-            self.line
-        )
+    pub(crate) fn err(&self, kind: VMErrorKind) -> VMError {
+        VMError::new(kind, self.stack_trace())
+    }
+
+    /// Captures the call stack, innermost frame first. The innermost frame is at the
+    /// current instruction; every other frame is at the call it is waiting on.
+    fn stack_trace(&self) -> Vec<StackFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(depth, frame)| StackFrame {
+                span: if depth == 0 { self.span } else { frame.span },
+                function: frame.function.map(str::to_string),
+            })
+            .collect()
     }
 
     fn pop(&mut self) -> Result<Value> {
         self.stack
             .pop()
-            .ok_or(self.err(VMErrorKind::PopFromEmptyStack))
+            .ok_or_else(|| self.err(VMErrorKind::PopFromEmptyStack))
+    }
+
+    fn peek(&self) -> Result<&Value> {
+        self.stack
+            .last()
+            .ok_or_else(|| self.err(VMErrorKind::PopFromEmptyStack))
+    }
+
+    pub(crate) fn constant<'c>(&self, constants: &'c [Value], offset: u8) -> Result<&'c Value> {
+        constants
+            .get(offset as usize)
+            .ok_or_else(|| self.err(VMErrorKind::InvalidBytecode("constant index out of range")))
+    }
+
+    /// Gives the running chunk a slot in `globals` for each of its global
+    /// names, all undefined except for the natives in the first slots.
+    pub(crate) fn reset_globals(&mut self, globals: &Globals) {
+        self.globals.clear();
+        self.globals
+            .extend(self.natives.iter().map(|(_, native)| Some(Value::from(native.get_object()))));
+        self.globals.resize(globals.names().len(), None);
+    }
+
+    pub(crate) fn global_slot(&self, slot: u8) -> Result<usize> {
+        match slot as usize {
+            slot if slot < self.globals.len() => Ok(slot),
+            _ => Err(self.err(VMErrorKind::InvalidBytecode("global slot out of range"))),
+        }
+    }
+
+    pub(crate) fn undefined_variable(&self, globals: &Globals, slot: u8) -> VMError {
+        match globals.name(slot) {
+            Some(name) => self.err(VMErrorKind::UndefinedVariable(name.to_string())),
+            None => self.err(VMErrorKind::InvalidBytecode("global slot out of range")),
+        }
+    }
+
+    fn local_slot(&self, offset: u8) -> Result<usize> {
+        match offset as usize {
+            slot if slot < self.stack.len() => Ok(slot),
+            _ => Err(self.err(VMErrorKind::InvalidBytecode("local slot out of range"))),
+        }
     }
 
     fn pop_num(&mut self) -> Result<f64> {
         let top_of_stack = self.pop()?;
         top_of_stack
             .as_num()
-            .ok_or(self.err(VMErrorKind::InvalidMathOperand))
+            .ok_or_else(|| self.err(VMErrorKind::InvalidMathOperand(top_of_stack.value_type())))
     }
 
     fn pop_nums(&mut self) -> Result<(f64, f64)> {
@@ -97,10 +350,29 @@ impl VM {
         let b = self.pop()?;
         match (a.as_num(), b.as_num()) {
             (Some(a), Some(b)) => Ok((a, b)),
-            _ => Err(self.err(VMErrorKind::InvalidMathOperands))
+            _ => Err(self.err(VMErrorKind::InvalidMathOperands(b.value_type(), a.value_type())))
         }
     }
 
+    pub(crate) fn add(&mut self, a: &Value, b: &Value) -> Result<Value> {
+        if let (Some(a), Some(b)) = (a.as_num(), b.as_num()) {
+            return Ok(Value::from(a + b));
+        }
+
+        match (a.as_obj(), b.as_obj()) {
+            (Some(a), Some(b))
+                if a.get_otype() == ObjectType::String
+                    && b.get_otype() == ObjectType::String =>
+            {
+                let (a, b) = (a.downcast().unwrap(), b.downcast().unwrap());
+                let new_obj = GC::new_concat_string(a, b);
+                let value = Value::from(new_obj.get_object());
+                self.allocations.push_front(new_obj);
+                Ok(value)
+            }
+            _ => Err(self.err(VMErrorKind::InvalidAddOperands(a.value_type(), b.value_type()))),
+        }
+    }
 
     pub fn stdrun(
         &mut self,
@@ -114,177 +386,259 @@ impl VM {
         chunk: &Chunk,
         output: &mut Output,
     ) -> Result<()> {
-        let mut allocations: LinkedList<GcHandle> = LinkedList::new();
-        let mut globals: HashMap<ObjString, Value> = HashMap::new();
-
-        let err = |kind: VMErrorKind, msg: Option<String>| -> VMError {
-            VMError {
-                line: chunk.debug_info[self.pc - 1],
-                kind,
-                msg
+        self.reset_globals(&chunk.globals);
+        self.start();
+
+        let result = if self.trace {
+            self.run_traced(chunk, output)
+        } else {
+            self.dispatch(chunk, output)
+        };
+        result.map_err(|err| self.locate(err, &chunk.debug_info))
+    }
+
+    /// Resets what a run leaves behind, so that a VM stopped by an error or
+    /// a budget can run again, and starts the clock for the timeout.
+    pub(crate) fn start(&mut self) {
+        self.stack.clear();
+        // Nothing refers to the strings of the last run any more.
+        self.allocations.clear();
+        self.pc = 0;
+        self.frames.clear();
+        self.frames.push(CallFrame { function: None, span: SourceSpan::default() });
+        self.exit_code = None;
+        self.instructions = 0;
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.next_check = 0;
+    }
+
+    /// Counts the instruction about to run, failing if it is over budget.
+    /// Costs a single comparison unless a limit is due to be checked.
+    #[inline(always)]
+    pub(crate) fn tick(&mut self) -> Result<()> {
+        if self.instructions == self.next_check {
+            self.check_limits()?;
+        }
+        self.instructions += 1;
+        Ok(())
+    }
+
+    #[cold]
+    fn check_limits(&mut self) -> Result<()> {
+        if let Some(budget) = self.instruction_budget.filter(|&budget| self.instructions >= budget) {
+            return Err(self.err(VMErrorKind::BudgetExhausted(Budget::Instructions(budget))));
+        }
+        if let (Some(deadline), Some(timeout)) = (self.deadline, self.timeout) {
+            if Instant::now() >= deadline {
+                return Err(self.err(VMErrorKind::BudgetExhausted(Budget::Time(timeout))));
             }
+        }
+
+        let clock_check = self.deadline.map(|_| self.instructions + CLOCK_CHECK_INTERVAL);
+        self.next_check = match (self.instruction_budget, clock_check) {
+            (Some(budget), Some(clock_check)) => budget.min(clock_check),
+            (budget, clock_check) => budget.or(clock_check).unwrap_or(u64::MAX),
         };
+        Ok(())
+    }
 
-        while self.pc < chunk.code.len() {
-            let instr = chunk.code[self.pc];
-            self.line = chunk.debug_info[self.pc];
+    /// The interpreter loop. It keeps no per-instruction bookkeeping beyond
+    /// `tick`: the span of an instruction is only looked up by calls and,
+    /// once an instruction fails, by `locate`.
+    fn dispatch<Output: io::Write>(&mut self, chunk: &Chunk, output: &mut Output) -> Result<()> {
+        let code = chunk.code.as_slice();
+        while let Some(&instr) = code.get(self.pc) {
             self.pc += 1;
+            self.tick()?;
+            if self.execute(instr, chunk, output)?.is_break() {
+                break;
+            }
+        }
 
-            if self.trace {
-                print!("            [ ");
-                for value in self.stack.iter() {
-                    print!("{:?} | ", value);
-                }
-                println!("_ ]\n");
-
-                println!(
-                    "{:?}\n",
-                    TraceInfo {
-                        offset: self.pc - 1,
-                        op: instr,
-                        chunk
-                    }
-                );
+        Ok(())
+    }
+
+    /// `dispatch`, printing the stack and each instruction before it runs.
+    fn run_traced<Output: io::Write>(&mut self, chunk: &Chunk, output: &mut Output) -> Result<()> {
+        while let Some(&instr) = chunk.code.get(self.pc) {
+            print!("            [ ");
+            for value in self.stack.iter() {
+                print!("{:?} | ", value);
             }
+            println!("_ ]\n");
 
-            match instr {
-                Op::Return => print!("{:?}", self.pop()?),
-                Op::Constant { offset } => self.push(chunk.constants[offset as usize].clone()),
-                Op::Nil => self.push(Value::Nil),
-                Op::True => self.push(Value::Bool(true)),
-                Op::False => self.push(Value::Bool(false)),
-                Op::Negate => {
-                    let new_val = -self.pop_num()?;
-                    self.push(new_val.into());
+            println!(
+                "{:?}\n",
+                TraceInfo {
+                    offset: self.pc,
+                    op: instr,
+                    chunk
                 }
-                Op::Not => {
-                    let top_of_stack = self.pop()?;
-                    let new_val = match top_of_stack {
-                        Value::Nil => Ok(true),
-                        Value::Bool(val) => Ok(!val),
-                        _ => Err(self.err(VMErrorKind::InvalidMathOperand)),
-                        //_ => Err(self.type_err("Boolean or Nil", top_of_stack)),
-                    }?;
-                    self.push(new_val.into());
+            );
+
+            self.pc += 1;
+            self.tick()?;
+            if self.execute(instr, chunk, output)?.is_break() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Points an error raised by the script itself, rather than by a native
+    /// it called, at the failing instruction.
+    pub(crate) fn locate(&self, mut err: VMError, debug_info: &[SourceSpan]) -> VMError {
+        if let [frame] = &mut err.trace[..] {
+            frame.span = debug_info.get(self.pc.saturating_sub(1)).copied().unwrap_or_default();
+        }
+        err
+    }
+
+    /// Runs `instr`, the instruction before `self.pc`. Breaks once the script
+    /// has asked to exit.
+    #[inline(always)]
+    fn execute<Output: io::Write>(&mut self, instr: Op, chunk: &Chunk, output: &mut Output) -> Result<ControlFlow<()>> {
+        match instr {
+            Op::Return => {
+                let value = self.pop()?;
+                write!(output, "{:?}", value).unwrap()
+            },
+            Op::Constant { offset } => self.push(*self.constant(&chunk.constants, offset)?),
+            Op::Nil => self.push(Value::nil()),
+            Op::True => self.push(Value::from(true)),
+            Op::False => self.push(Value::from(false)),
+            Op::Negate => {
+                let new_val = -self.pop_num()?;
+                self.push(new_val.into());
+            }
+            Op::Not => {
+                let new_val = self.pop()?.is_falsey();
+                self.push(new_val.into());
+            }
+            Op::Add => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let sum = self.add(&a, &b)?;
+                self.push(sum)
+            }
+            Op::Subtract | Op::Multiply | Op::Divide => {
+                let (b, a) = self.pop_nums()?;
+                let r = match instr {
+                    Op::Subtract => a - b,
+                    Op::Multiply => a * b,
+                    Op::Divide => a / b,
+                    _ => unreachable!(),
+                };
+                self.push(r.into())
+            }
+            Op::Greater | Op::Less | Op::GreaterEqual | Op::LessEqual => {
+                let (b, a) = self.pop_nums()?;
+                let r = match instr {
+                    Op::Greater => a > b,
+                    Op::Less => a < b,
+                    Op::GreaterEqual => a >= b,
+                    Op::LessEqual => a <= b,
+                    _ => unreachable!(),
+                };
+                self.push(r.into())
+            }
+            Op::Equal | Op::NotEqual => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let r = (a == b) == (instr == Op::Equal);
+                self.push(r.into())
+            }
+            Op::Print => {
+                let value = self.pop()?;
+                writeln!(output, "{}", value).unwrap()
+            },
+            Op::Pop => {
+                self.pop()?;
+            },
+            Op::DefineGlobal { slot } => {
+                let index = self.global_slot(slot)?;
+                self.globals[index] = Some(self.pop()?);
+            },
+            Op::GetGlobal { slot } => {
+                let index = self.global_slot(slot)?;
+                match self.globals[index] {
+                    Some(value) => self.push(value),
+                    None => return Err(self.undefined_variable(&chunk.globals, slot)),
                 }
-                Op::Add => {
-                    let b = self.pop()?;
-                    match b {
-                        Value::Number(num) => {
-                            let a = self.pop_num().or(Err(self.err(VMErrorKind::InvalidAddOperands)))?;
-                            self.push(Value::from(num + a));
-                            Ok(())
-                        }
-                        Value::Obj(b) => match b.get_otype() {
-                            ObjectType::String => {
-                                let a = self.pop()?;
-                                match a {
-                                    Value::Obj(a) => match a.get_otype() {
-                                        ObjectType::String => {
-                                            let (a, b) = (a.downcast().unwrap(), b.downcast().unwrap());
-                                            let new_obj = GC::new_concat_string(a, b);
-                                            self.push(Value::from(new_obj.get_object()));
-                                            allocations.push_front(new_obj);
-                                            Ok(())
-                                        }
-                                    },
-                                    _ => Err(self.err(VMErrorKind::InvalidAddOperands)),
-                                }
-                            }
-                        },
-                        _ => Err(self.err(VMErrorKind::InvalidAddOperands)),
-                    }?
+            },
+            Op::SetGlobal { slot } => {
+                let index = self.global_slot(slot)?;
+                if self.globals[index].is_none() {
+                    return Err(self.undefined_variable(&chunk.globals, slot));
                 }
-                Op::Subtract | Op::Multiply | Op::Divide => {
-                    let (b, a) = self.pop_nums()?;
-                    let r = match instr {
-                        Op::Subtract => a - b,
-                        Op::Multiply => a * b,
-                        Op::Divide => a / b,
-                        _ => unreachable!(),
-                    };
-                    self.push(r.into())
+                self.globals[index] = Some(*self.peek()?);
+            },
+            Op::GetLocal { offset } => {
+                let slot = self.local_slot(offset)?;
+                self.push(self.stack[slot])
+            },
+            Op::SetLocal { offset } => {
+                let slot = self.local_slot(offset)?;
+                self.stack[slot] = *self.peek()?
+            },
+            Op::GetLocal2 { first, second } => {
+                // `second` may be the slot the first push creates.
+                let first = self.local_slot(first)?;
+                self.push(self.stack[first]);
+                let second = self.local_slot(second)?;
+                self.push(self.stack[second])
+            },
+            Op::AddLocalConst { slot, offset } => {
+                let local = self.stack[self.local_slot(slot)?];
+                let sum = self.add(&local, self.constant(&chunk.constants, offset)?)?;
+                self.push(sum)
+            },
+            Op::IncrementLocal { slot, offset } => {
+                let slot = self.local_slot(slot)?;
+                let local = self.stack[slot];
+                self.stack[slot] = self.add(&local, self.constant(&chunk.constants, offset)?)?
+            },
+            Op::Call { arg_count } => {
+                // Natives see the call in the stack trace.
+                self.span = chunk.debug_info.get(self.pc - 1).copied().unwrap_or_default();
+                let callee_slot = self
+                    .stack
+                    .len()
+                    .checked_sub(arg_count as usize + 1)
+                    .ok_or_else(|| self.err(VMErrorKind::PopFromEmptyStack))?;
+
+                let callee = &self.stack[callee_slot];
+                let native = callee
+                    .as_obj()
+                    .and_then(|object| object.downcast::<ObjNative>())
+                    .ok_or_else(|| self.err(VMErrorKind::NotCallable(callee.value_type())))?;
+
+                if native.arity() != arg_count {
+                    return Err(self.err(VMErrorKind::ArityMismatch {
+                        expected: native.arity(),
+                        got: arg_count,
+                    }));
                 }
-                Op::Greater | Op::Less => {
-                    let (b, a) = self.pop_nums()?;
-                    let r = match instr {
-                        Op::Greater => a > b,
-                        Op::Less => a < b,
-                        _ => unreachable!(),
-                    };
-                    self.push(r.into())
+
+                let args = self.stack.split_off(callee_slot + 1);
+                if let Some(caller) = self.frames.last_mut() {
+                    caller.span = self.span;
                 }
-                Op::Equal => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    let r = a == b;
-                    self.push(r.into())
+                self.frames.push(CallFrame { function: Some(native.name()), span: self.span });
+                let result = native.function()(self, &args);
+                self.frames.pop();
+                let result = result?;
+                self.stack.truncate(callee_slot);
+                self.push(result);
+
+                if self.exit_code.is_some() {
+                    return Ok(ControlFlow::Break(()));
                 }
-                Op::Print => {
-                    let value = self.pop()?;
-                    writeln!(output, "{}", value).unwrap()
-                },
-                Op::Pop => {
-                    self.pop()?;
-                },
-                Op::DefineGlobal { offset } => {
-                    let ident = chunk.constants[offset as usize].clone();
-                    if let Value::Obj(name) = ident {
-                        let name = name.downcast::<ObjString>().unwrap();
-                        globals.entry(name).insert_entry(self.pop()?);
-                    } else {
-                        unreachable!()
-                    };
-                },
-                Op::GetGlobal { offset } => {
-                    let ident = match chunk.constants[offset as usize] {
-                        Value::Obj(object) => object.downcast::<ObjString>().unwrap(),
-                        _ => todo!(),
-                    };
-
-                    if let Some(value) = globals.get(&ident) {
-                        self.push(value.clone());
-
-                        Ok(())
-                    } else {
-                        Err(VMError {
-                            line: self.line,
-                            kind: VMErrorKind::UndefinedVariable,
-                            msg: Some(format!("Undefined variable '{}'.", ident)),
-                        })
-                    }?
-                },
-                Op::SetGlobal { offset } => {
-                    let ident = match chunk.constants[offset as usize] {
-                        Value::Obj(object) => object.downcast::<ObjString>().unwrap(),
-                        _ => todo!(),
-                    };
-
-                    match globals.entry(ident) {
-                        hash_map::Entry::Occupied(mut entry) => {
-                            let v = self.stack.last().unwrap();
-                            entry.insert(v.clone());
-                            Ok(())
-                        },
-                        hash_map::Entry::Vacant(_) => {
-                            Err(VMError {
-                                line: self.line,
-                                kind: VMErrorKind::UndefinedVariable,
-                                msg: Some(format!("Undefined variable '{}'.", ident)),
-                            })
-                        },
-                    }?
-                },
-                Op::GetLocal { offset } => {
-                    self.push(self.stack[offset as usize].clone())
-                },
-                Op::SetLocal { offset } => {
-                    self.stack[offset as usize] = self.stack.last().unwrap().clone()
-                },
-            }
+            },
         }
 
-        Ok(())
+        Ok(ControlFlow::Continue(()))
     }
 }
 
@@ -294,9 +648,12 @@ mod tests {
 
     use crate::{gc::GC, vm::VMErrorKind};
 
-    use super::{Chunk, Op, VMError, Value, VM};
+    use std::time::Duration;
+
+    use super::{Budget, Chunk, Globals, Op, SourceSpan, StackFrame, VMError, Value, ValueType, VM};
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn simple_arithmetic() -> Result<(), VMError>{
         let chunk = Chunk::new_with(
             vec![
@@ -341,10 +698,112 @@ mod tests {
         let mut vm = VM::new();
         assert_eq!(
             vm.stdrun(&chunk).unwrap_err().kind,
-            VMErrorKind::InvalidMathOperand
+            VMErrorKind::InvalidMathOperand(ValueType::Nil)
         );
     }
 
+    #[test]
+    fn error_captures_stack_trace() {
+        let chunk = Chunk::new_with(
+            vec![Op::Nil, Op::Nil, Op::Negate],
+            vec![1, 2, 3],
+            vec![],
+            LinkedList::new(),
+        );
+
+        let mut vm = VM::new();
+        let err = vm.stdrun(&chunk).unwrap_err();
+        assert_eq!(err.trace, vec![StackFrame { span: SourceSpan::from(3), function: None }]);
+        assert_eq!(err.to_string(), "Operand must be a number.\n[line 3] in script");
+        assert_eq!(err.diagnostic().message, "Operand must be a number, got nil.");
+    }
+
+    #[test]
+    fn error_kinds_name_operand_types() {
+        let string = GC::new_string("str");
+        let chunk = |code: Vec<Op>| {
+            let len = code.len();
+            Chunk::new_with(
+                code,
+                vec![1; len],
+                vec![Value::from(1.0), Value::from(string.get_object())],
+                LinkedList::new(),
+            )
+        };
+
+        let cases = [
+            (
+                vec![Op::Constant { offset: 0 }, Op::Nil, Op::Add],
+                VMErrorKind::InvalidAddOperands(ValueType::Number, ValueType::Nil),
+            ),
+            (
+                vec![Op::Constant { offset: 1 }, Op::True, Op::Less],
+                VMErrorKind::InvalidMathOperands(ValueType::String, ValueType::Bool),
+            ),
+            (
+                vec![Op::Constant { offset: 1 }, Op::Negate],
+                VMErrorKind::InvalidMathOperand(ValueType::String),
+            ),
+            (vec![Op::Pop], VMErrorKind::PopFromEmptyStack),
+        ];
+
+        for (code, kind) in cases {
+            assert_eq!(VM::new().stdrun(&chunk(code)).unwrap_err().kind, kind);
+        }
+
+    }
+
+    #[test]
+    fn error_kind_messages() {
+        let cases = [
+            (
+                VMErrorKind::InvalidAddOperands(ValueType::Number, ValueType::Nil),
+                "Operands must be two numbers or two strings, got number and nil.",
+            ),
+            (
+                VMErrorKind::InvalidMathOperands(ValueType::String, ValueType::Bool),
+                "Operands must be numbers, got string and boolean.",
+            ),
+            (VMErrorKind::InvalidMathOperand(ValueType::Native), "Operand must be a number, got native function."),
+            (VMErrorKind::UndefinedVariable("x".to_string()), "Undefined variable 'x'."),
+            (VMErrorKind::PopFromEmptyStack, "Stack underflow."),
+            (VMErrorKind::NotCallable(ValueType::Nil), "Can only call functions and classes, got nil."),
+            (VMErrorKind::ArityMismatch { expected: 1, got: 2 }, "Expected 1 arguments but got 2."),
+            (VMErrorKind::ExpectedStringArgument(ValueType::Number), "Argument must be a string, got number."),
+            (VMErrorKind::ExpectedIntegerArgument, "Argument must be an integer."),
+            (VMErrorKind::InvalidExitCode, "Exit code must be between 0 and 255."),
+            (
+                VMErrorKind::InvalidBytecode("local slot out of range"),
+                "Invalid bytecode: local slot out of range.",
+            ),
+            (
+                VMErrorKind::BudgetExhausted(Budget::Instructions(1000)),
+                "Execution stopped after 1000 instructions.",
+            ),
+            (
+                VMErrorKind::BudgetExhausted(Budget::Time(Duration::from_millis(20))),
+                "Execution stopped after 20ms.",
+            ),
+        ];
+
+        for (kind, message) in cases {
+            assert_eq!(kind.detailed(), message);
+        }
+
+        let plain = [
+            (VMErrorKind::InvalidAddOperands(ValueType::Number, ValueType::Nil), "Operands must be two numbers or two strings."),
+            (VMErrorKind::InvalidMathOperands(ValueType::String, ValueType::Bool), "Operands must be numbers."),
+            (VMErrorKind::InvalidMathOperand(ValueType::Native), "Operand must be a number."),
+            (VMErrorKind::NotCallable(ValueType::Nil), "Can only call functions and classes."),
+            (VMErrorKind::ExpectedStringArgument(ValueType::Number), "Argument must be a string."),
+            (VMErrorKind::UndefinedVariable("x".to_string()), "Undefined variable 'x'."),
+        ];
+
+        for (kind, message) in plain {
+            assert_eq!(kind.to_string(), message);
+        }
+    }
+
     #[test]
     fn simple_booleans() -> Result<(), VMError> {
         let chunk = Chunk::new_with(
@@ -357,7 +816,25 @@ mod tests {
         let mut vm = VM::new();
         vm.stdrun(&chunk)?;
 
-        assert_eq!(vm.stack, vec![Value::Bool(true)]);
+        assert_eq!(vm.stack, vec![Value::from(true)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn comparisons_with_nan_are_false() -> Result<(), VMError> {
+        let nan = Value::from(f64::NAN);
+        let ops = [Op::Greater, Op::GreaterEqual, Op::Less, Op::LessEqual, Op::Equal, Op::NotEqual];
+
+        for op in ops {
+            let code = vec![Op::Constant { offset: 0 }, Op::Constant { offset: 0 }, op];
+            let chunk = Chunk::new_with(code, vec![1; 3], vec![nan], LinkedList::new());
+
+            let mut vm = VM::new();
+            vm.stdrun(&chunk)?;
+
+            assert_eq!(vm.stack, vec![Value::from(op == Op::NotEqual)], "{:?}", op);
+        }
 
         Ok(())
     }
@@ -369,60 +846,194 @@ mod tests {
         let mut vm = VM::new();
         vm.stdrun(&chunk)?;
 
-        assert_eq!(vm.stack, vec![Value::Bool(true)]);
+        assert_eq!(vm.stack, vec![Value::from(true)]);
 
         Ok(())
     }
 
     #[test]
-    fn define_read_globals() -> Result<(), VMError> {
-        let var = GC::new_string("global");
+    fn not_follows_truthiness() -> Result<(), VMError> {
+        let string = GC::new_string("");
+        let chunk = Chunk::new_with(
+            vec![
+                Op::Constant { offset: 0 },
+                Op::Not,
+                Op::Constant { offset: 1 },
+                Op::Not,
+                Op::True,
+                Op::Not,
+                Op::Nil,
+                Op::Not,
+                Op::Not,
+            ],
+            vec![1; 9],
+            vec![Value::from(0.0), Value::from(string.get_object())],
+            LinkedList::new(),
+        );
+
+        let mut vm = VM::new();
+        vm.stdrun(&chunk)?;
+
+        assert_eq!(
+            vm.stack,
+            vec![Value::from(false), Value::from(false), Value::from(false), Value::from(false)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_local2_reads_its_own_push() -> Result<(), VMError> {
         use Op::*;
         let chunk = Chunk::new_with(
+            vec![Constant { offset: 0 }, GetLocal2 { first: 0, second: 1 }],
+            vec![1; 2],
+            vec![Value::from(7.0)],
+            LinkedList::new()
+        );
+
+        let mut vm = VM::new();
+        vm.stdrun(&chunk)?;
+
+        assert_eq!(vm.stack, vec![Value::from(7.0); 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn define_read_globals() -> Result<(), VMError> {
+        let mut globals = Globals::default();
+        let slot = globals.slot("global").unwrap();
+        use Op::*;
+        let mut chunk = Chunk::new_with(
             vec![
                 Constant { offset: 0 },
-                DefineGlobal { offset: 1 },
-                Constant { offset: 2 },
-                GetGlobal { offset: 1 },
+                DefineGlobal { slot },
+                Constant { offset: 1 },
+                GetGlobal { slot },
                 Multiply,
             ],
             vec![1; 5],
-            vec![Value::from(5.0), Value::from(var.get_object()), Value::from(6.0)],
+            vec![Value::from(5.0), Value::from(6.0)],
             LinkedList::new()
         );
+        chunk.globals = globals;
 
         let mut vm = VM::new();
         vm.stdrun(&chunk)?;
 
-        assert_eq!(vm.stack, vec![Value::Number(30.0)]);
+        assert_eq!(vm.stack, vec![Value::from(30.0)]);
 
         Ok(())
     }
 
     #[test]
     fn define_write_read_globals() -> Result<(), VMError> {
-        let var = GC::new_string("global");
+        let mut globals = Globals::default();
+        let slot = globals.slot("global").unwrap();
         use Op::*;
-        let chunk = Chunk::new_with(
+        let mut chunk = Chunk::new_with(
             vec![
                 Constant { offset: 0 },
-                DefineGlobal { offset: 1 },
-                GetGlobal { offset: 1 },
-                Constant { offset: 2 },
+                DefineGlobal { slot },
+                GetGlobal { slot },
+                Constant { offset: 1 },
                 Add,
-                SetGlobal { offset: 1 },
+                SetGlobal { slot },
                 Pop,
-                GetGlobal { offset: 1 },
+                GetGlobal { slot },
             ],
             vec![1; 8],
-            vec![Value::from(5.0), Value::from(var.get_object()), Value::from(6.0)],
+            vec![Value::from(5.0), Value::from(6.0)],
             LinkedList::new()
         );
+        chunk.globals = globals;
 
         let mut vm = VM::new();
         vm.stdrun(&chunk)?;
 
-        assert_eq!(vm.stack, vec![Value::Number(11.0)]);
+        assert_eq!(vm.stack, vec![Value::from(11.0)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn global_slots() -> Result<(), VMError> {
+        let mut globals = Globals::default();
+        let (a, b) = (globals.slot("a").unwrap(), globals.slot("b").unwrap());
+        let chunk = |code: Vec<Op>| {
+            let mut chunk = Chunk::new_with(code.clone(), vec![1; code.len()], vec![], LinkedList::new());
+            chunk.globals = globals.clone();
+            chunk
+        };
+        use Op::*;
+
+        let mut vm = VM::new();
+        vm.stdrun(&chunk(vec![Nil, DefineGlobal { slot: a }, GetGlobal { slot: a }, GetGlobal { slot: 0 }]))?;
+        assert_eq!(vm.stack[0], Value::nil());
+        assert_eq!(vm.stack[1].value_type(), ValueType::Native);
+
+        for code in [vec![GetGlobal { slot: b }], vec![Nil, SetGlobal { slot: b }, Nil, DefineGlobal { slot: b }]] {
+            let err = VM::new().stdrun(&chunk(code)).unwrap_err();
+            assert_eq!(err.kind, VMErrorKind::UndefinedVariable("b".to_string()));
+        }
+
+        let err = VM::new().stdrun(&chunk(vec![GetGlobal { slot: b + 1 }])).unwrap_err();
+        assert_eq!(err.kind, VMErrorKind::InvalidBytecode("global slot out of range"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn runs_free_the_strings_of_earlier_runs() -> Result<(), VMError> {
+        let string = GC::new_string("str");
+        use Op::*;
+        let chunk = Chunk::new_with(
+            vec![Constant { offset: 0 }, Constant { offset: 0 }, Add, Constant { offset: 0 }, Add],
+            vec![1; 5],
+            vec![Value::from(string.get_object())],
+            LinkedList::new(),
+        );
+
+        let mut vm = VM::new();
+        for _ in 0..100 {
+            vm.stdrun(&chunk)?;
+            assert_eq!(vm.allocations.len(), 2);
+        }
+        assert_eq!(vm.stack[0].to_string(), "strstrstr");
+
+        Ok(())
+    }
+
+    #[test]
+    fn budgets_stop_runs() -> Result<(), VMError> {
+        let chunk = Chunk::new_with(
+            vec![Op::True, Op::Not, Op::Not, Op::Not],
+            vec![1, 2, 3, 4],
+            vec![],
+            LinkedList::new(),
+        );
+        let mut vm = VM::new();
+
+        vm.set_instruction_budget(Some(2));
+        let err = vm.stdrun(&chunk).unwrap_err();
+        assert_eq!(err.kind, VMErrorKind::BudgetExhausted(Budget::Instructions(2)));
+        assert_eq!(err.trace, vec![StackFrame { span: SourceSpan::from(3), function: None }]);
+        assert_eq!((vm.instructions_executed(), &vm.stack[..]), (2, &[Value::from(false)][..]));
+
+        vm.set_instruction_budget(Some(4));
+        vm.stdrun(&chunk)?;
+        assert_eq!((vm.instructions_executed(), &vm.stack[..]), (4, &[Value::from(false)][..]));
+
+        vm.set_timeout(Some(Duration::ZERO));
+        let err = vm.stdrun(&chunk).unwrap_err();
+        assert_eq!(err.kind, VMErrorKind::BudgetExhausted(Budget::Time(Duration::ZERO)));
+        assert_eq!(vm.instructions_executed(), 0);
+
+        vm.set_instruction_budget(None);
+        vm.set_timeout(Some(Duration::from_secs(60)));
+        vm.stdrun(&chunk)?;
+        assert_eq!(vm.stack, vec![Value::from(false)]);
 
         Ok(())
     }
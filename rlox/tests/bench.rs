@@ -0,0 +1,92 @@
+//! Runs `rlox bench` on the checked-in benchmarks, and on a scratch
+//! directory of benchmarks to check the report, the saved baseline and the
+//! comparison against it.
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::Duration;
+
+use rlox::bench::Measurement;
+
+fn rlox_bench(args: &[&str], dir: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("bench")
+        .args(args)
+        .arg(dir)
+        .output()
+        .expect("failed to start rlox")
+}
+
+#[test]
+fn bench() {
+    let dir = std::env::temp_dir().join(format!("rlox-bench-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("straight.lox"), "var a = 1; { var b = a + 2; print b; }").unwrap();
+    fs::write(dir.join("notes.txt"), "not a benchmark").unwrap();
+
+    let baseline = dir.join("baseline.json");
+    let save = format!("--save-baseline={}", baseline.display());
+    let output = rlox_bench(&["--runs=3", &save], &dir);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert_eq!(lines.len(), 2, "{}", stdout);
+
+    let saved = rlox::bench::parse_baseline(&fs::read_to_string(&baseline).unwrap()).unwrap();
+    assert_eq!(saved.len(), 1);
+    let instructions = saved[0].instructions;
+    assert_eq!(saved[0].name, "straight.lox");
+    assert!(instructions > 0);
+    assert!(lines[1].starts_with("straight.lox ") && lines[1].ends_with(&format!(" {}", instructions)), "{}", stdout);
+
+    let compare = format!("--baseline={}", baseline.display());
+    let output = rlox_bench(&["--threshold=1000000", &compare], &dir);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stdout));
+
+    let fewer = instructions - 1;
+    let faster = Measurement { min: Duration::ZERO, median: Duration::ZERO, instructions: fewer, ..saved[0].clone() };
+    fs::write(&baseline, rlox::bench::to_json(&[faster])).unwrap();
+    let output = rlox_bench(&[&compare], &dir);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    let regression = format!("    regressed: {} instructions, baseline {}\n", instructions, fewer);
+    assert!(stdout.contains(&regression), "{}", stdout);
+    assert!(stdout.ends_with("1 benchmark(s) regressed\n"), "{}", stdout);
+
+    fs::write(dir.join("broken.lox"), "print ;").unwrap();
+    let output = rlox_bench(&["--runs=1"], &dir);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(stdout.contains("broken.lox               failed: [line 1] Error at ';': Expect expression.\n"), "{}", stdout);
+    assert!(stdout.ends_with("1 benchmark(s) failed\n"), "{}", stdout);
+
+    assert_eq!(rlox_bench(&["--runs=0"], &dir).status.code(), Some(64));
+    assert_eq!(rlox_bench(&[], &dir.join("notes.txt")).status.code(), Some(1));
+    fs::create_dir(dir.join("empty")).unwrap();
+    assert_eq!(rlox_bench(&[], &dir.join("empty")).status.code(), Some(66));
+    assert_eq!(rlox_bench(&["--baseline=missing.json"], &dir).status.code(), Some(66));
+
+    fs::remove_file(dir.join("broken.lox")).unwrap();
+    fs::write(dir.join("loop.lox"), "var i = 0;\nwhile (i < 10) i = i + 1;").unwrap();
+    let output = rlox_bench(&["--runs=1"], &dir);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert!(stdout.contains("loop.lox                 unsupported: [line 2] Error at 'while': Expect expression.\n"), "{}", stdout);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checked_in_benchmarks_run() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benchmarks");
+    let output = rlox_bench(&["--runs=1"], &dir);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+
+    let benchmarks = fs::read_dir(&dir).unwrap().count();
+    assert!(benchmarks > 0);
+    assert_eq!(stdout.lines().count(), benchmarks + 1, "{}", stdout);
+    assert!(!stdout.contains(" failed: "), "{}", stdout);
+}
@@ -0,0 +1,275 @@
+//! Runs rlox over the Crafting Interpreters test corpus shared with jlox.
+//!
+//! Every `.lox` file is annotated with its expected behaviour:
+//!
+//! - `// expect: <text>` expects `<text>` as the next line on stdout,
+//! - `// expect runtime error: <msg>` expects `<msg>` on stderr, followed by
+//!   the `[line N]` of the annotation, and exit code 70,
+//! - `// Error ...` and `// [line N] Error ...` expect that compile error on
+//!   stderr and exit code 65. Lines tagged `[java line N]` only apply to jlox
+//!   and are skipped; `[c line N]` lines apply to rlox.
+//!
+//! rlox does not implement the whole language yet, so the test prints a
+//! per-chapter report (visible with `--nocapture`) and only fails when a
+//! chapter listed in `PASSING_CHAPTERS` regresses. The corpus runs once per
+//! backend: the stack VM and, with `--registers`, the register VM.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CORPUS: &str = "../jlox/lox/src/test/resources/xyz/ctsk/lox";
+
+/// Chapters for the earlier tree-walking parts of the book, not meant for clox.
+const SKIPPED_CHAPTERS: [&str; 3] = ["benchmark", "expressions", "scanning"];
+
+/// Chapters in which every test passes; a failure in these fails the build.
+const PASSING_CHAPTERS: [&str; 6] = ["(top level)", "bool", "comments", "nil", "print", "string"];
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expectations = Expectations::default();
+
+        for (idx, line) in source.lines().enumerate() {
+            let line_number = idx + 1;
+
+            if let Some((_, text)) = line.split_once("// expect: ") {
+                expectations.output.push(text.to_string());
+            } else if let Some((_, msg)) = line.split_once("// expect runtime error: ") {
+                expectations.runtime_error = Some((msg.to_string(), line_number));
+            } else if let Some((_, error)) = line.split_once("// Error") {
+                expectations
+                    .compile_errors
+                    .push(format!("[line {}] Error{}", line_number, error));
+            } else if let Some((_, annotated)) = line.split_once("// [") {
+                let Some((location, error)) = annotated.split_once("] Error") else {
+                    continue;
+                };
+
+                let location = match location.strip_prefix("c ") {
+                    Some(location) => location,
+                    None if location.starts_with("java ") => continue,
+                    None => location,
+                };
+
+                if let Some(error_line) = location.strip_prefix("line ") {
+                    expectations
+                        .compile_errors
+                        .push(format!("[line {}] Error{}", error_line, error));
+                }
+            }
+        }
+
+        expectations
+    }
+
+    fn expected_exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+/// Passed test count and failed tests with their mismatches, per chapter.
+type Report = BTreeMap<String, (usize, Vec<(PathBuf, Vec<String>)>)>;
+
+struct Outcome {
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+fn run_rlox(flags: &[&str], path: &Path) -> Outcome {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(flags)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start rlox");
+
+    let mut stdout_pipe = child.stdout.take().unwrap();
+    let mut stderr_pipe = child.stderr.take().unwrap();
+    let stdout_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stdout_pipe.read_to_end(&mut buffer);
+        buffer
+    });
+    let stderr_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stderr_pipe.read_to_end(&mut buffer);
+        buffer
+    });
+
+    let deadline = Instant::now() + TIMEOUT;
+    let exit_code = loop {
+        match child.try_wait().expect("failed to wait on rlox") {
+            Some(status) => break status.code(),
+            None if Instant::now() > deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            None => thread::sleep(Duration::from_millis(5)),
+        }
+    };
+
+    Outcome {
+        exit_code,
+        stdout: String::from_utf8_lossy(&stdout_reader.join().unwrap()).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr_reader.join().unwrap()).into_owned(),
+    }
+}
+
+/// Returns a description of every mismatch between the expectations and the outcome.
+fn check(expectations: &Expectations, outcome: &Outcome) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    let Some(exit_code) = outcome.exit_code else {
+        return vec!["timed out or crashed".to_string()];
+    };
+
+    let stderr: Vec<&str> = outcome.stderr.lines().collect();
+
+    if let Some((msg, line)) = &expectations.runtime_error {
+        let expected_line = format!("[line {}]", line);
+        match stderr.as_slice() {
+            [first, second, ..] if first == msg && second.starts_with(&expected_line) => {}
+            _ => failures.push(format!(
+                "expected runtime error '{}' at {}, got {:?}",
+                msg, expected_line, stderr
+            )),
+        }
+    } else {
+        for error in &expectations.compile_errors {
+            if !stderr.contains(&error.as_str()) {
+                failures.push(format!("missing compile error '{}'", error));
+            }
+        }
+
+        for line in &stderr {
+            if !expectations.compile_errors.iter().any(|error| error == line) {
+                failures.push(format!("unexpected stderr '{}'", line));
+            }
+        }
+    }
+
+    let stdout: Vec<&str> = outcome.stdout.lines().collect();
+    if stdout != expectations.output {
+        failures.push(format!(
+            "expected output {:?}, got {:?}",
+            expectations.output, stdout
+        ));
+    }
+
+    if exit_code != expectations.expected_exit_code() {
+        failures.push(format!(
+            "expected exit code {}, got {}",
+            expectations.expected_exit_code(),
+            exit_code
+        ));
+    }
+
+    failures
+}
+
+fn collect_tests(dir: &Path, tests: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).expect("failed to read test corpus") {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_tests(&path, tests);
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            tests.push(path);
+        }
+    }
+}
+
+/// The chapter is the directory below the corpus root; top-level files form their own group.
+fn chapter(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap();
+    match relative.parent() {
+        Some(parent) if parent != Path::new("") => parent.display().to_string(),
+        _ => "(top level)".to_string(),
+    }
+}
+
+/// Runs the corpus with `flags` passed to rlox.
+fn run_corpus(flags: &[&str]) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join(CORPUS);
+    let mut tests = Vec::new();
+    collect_tests(&root, &mut tests);
+    tests.sort();
+
+    let mut results = Report::new();
+
+    for path in tests {
+        let chapter = chapter(&root, &path);
+        if SKIPPED_CHAPTERS.contains(&chapter.as_str()) {
+            continue;
+        }
+
+        let source = fs::read_to_string(&path).unwrap();
+        let failures = check(&Expectations::parse(&source), &run_rlox(flags, &path));
+
+        let (passed, failed) = results.entry(chapter).or_default();
+        if failures.is_empty() {
+            *passed += 1;
+        } else {
+            failed.push((path, failures));
+        }
+    }
+
+    let (mut total_passed, mut total) = (0, 0);
+    let mut regressions = Vec::new();
+
+    println!("{:<20} {:>7}", "chapter", "passed");
+    for (chapter, (passed, failed)) in &results {
+        let count = passed + failed.len();
+        println!("{:<20} {:>3}/{:<3}", chapter, passed, count);
+
+        total_passed += passed;
+        total += count;
+
+        if PASSING_CHAPTERS.contains(&chapter.as_str()) {
+            regressions.extend(failed.iter());
+        }
+    }
+    println!("{:<20} {:>3}/{:<3}", "total", total_passed, total);
+
+    for (path, failures) in &regressions {
+        println!("\nFAIL {}", path.display());
+        for failure in failures {
+            println!("    {}", failure);
+        }
+    }
+
+    assert!(regressions.is_empty(), "{} conformance test(s) regressed", regressions.len());
+}
+
+#[test]
+fn conformance() {
+    run_corpus(&[]);
+}
+
+#[test]
+fn conformance_registers() {
+    run_corpus(&["--registers"]);
+}
@@ -0,0 +1,160 @@
+//! Runs the fuzz targets of `rlox::fuzz` offline, without cargo-fuzz.
+//!
+//! Each target gets the `.lox` files of the conformance corpus as seeds (the
+//! `chunk` target gets their compiled chunks), the inputs checked in under
+//! `fuzz/regressions/<target>/`, and a fixed set of mutations of both. The
+//! mutations are seeded, so a failure here always reproduces.
+//!
+//! `cargo test --test fuzz -- --ignored` writes the seeds to
+//! `fuzz/corpus/<target>/`, where `cargo fuzz run <target>` picks them up.
+
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+const CORPUS: &str = "../jlox/lox/src/test/resources/xyz/ctsk/lox";
+const REGRESSIONS: &str = "fuzz/regressions";
+const SEED_CORPUS: &str = "fuzz/corpus";
+
+const MUTATIONS_PER_SEED: usize = 16;
+
+type Target = (&'static str, fn(&[u8]));
+
+const TARGETS: [Target; 4] = [
+    ("compile", rlox::fuzz::compile),
+    ("compilers", rlox::fuzz::compilers),
+    ("run", rlox::fuzz::run),
+    ("chunk", rlox::fuzz::chunk),
+];
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Named seed inputs for the target.
+fn seeds(target: &str) -> Vec<(String, Vec<u8>)> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut seeds = Vec::new();
+
+    let mut lox_files = Vec::new();
+    collect_files(&root.join(CORPUS), &mut lox_files);
+    lox_files.retain(|path| path.extension().is_some_and(|ext| ext == "lox"));
+    lox_files.sort();
+
+    for path in lox_files {
+        let source = fs::read(&path).unwrap();
+        let name = path.strip_prefix(root.join(CORPUS)).unwrap().display().to_string();
+        let input = match target {
+            "chunk" => rlox::fuzz::chunk_seed(&source),
+            _ => Some(source),
+        };
+
+        if let Some(input) = input {
+            seeds.push((name.replace('/', "_"), input));
+        }
+    }
+
+    let mut regressions = Vec::new();
+    collect_files(&root.join(REGRESSIONS).join(target), &mut regressions);
+    regressions.sort();
+
+    for path in regressions {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        seeds.push((name, fs::read(&path).unwrap()));
+    }
+
+    seeds
+}
+
+/// xorshift64*, so that mutations are the same on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % bound.max(1) as u64) as usize
+    }
+}
+
+/// Applies a few random edits: byte flips, insertions, deletions, duplications and truncation.
+fn mutate(input: &[u8], rng: &mut Rng) -> Vec<u8> {
+    let mut bytes = input.to_vec();
+
+    for _ in 0..1 + rng.below(4) {
+        let at = rng.below(bytes.len() + 1);
+        let len = rng.below((bytes.len() - at).min(16) + 1);
+        match rng.below(5) {
+            0 if at < bytes.len() => bytes[at] ^= 1 << rng.below(8),
+            1 => bytes.insert(at, rng.below(256) as u8),
+            2 => {
+                bytes.drain(at..at + len);
+            }
+            3 => {
+                let copy = bytes[at..at + len].to_vec();
+                bytes.splice(at..at, copy);
+            }
+            _ => bytes.truncate(at.max(bytes.len() / 2)),
+        }
+    }
+
+    bytes
+}
+
+#[test]
+fn fuzz_corpus() {
+    let mut failures = Vec::new();
+    let mut count = 0;
+
+    for (target, run) in TARGETS {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for (name, seed) in seeds(target) {
+            let mut inputs = vec![(name.clone(), seed.clone())];
+            for i in 0..MUTATIONS_PER_SEED {
+                inputs.push((format!("{} (mutation {})", name, i), mutate(&seed, &mut rng)));
+            }
+
+            for (name, input) in inputs {
+                count += 1;
+                if panic::catch_unwind(|| run(&input)).is_err() {
+                    failures.push(format!("{}: {} {:?}", target, name, String::from_utf8_lossy(&input)));
+                }
+            }
+        }
+    }
+
+    println!("{} inputs", count);
+    for failure in &failures {
+        println!("FAIL {}", failure);
+    }
+
+    assert!(failures.is_empty(), "{} fuzz input(s) panicked", failures.len());
+}
+
+#[test]
+#[ignore = "writes the cargo-fuzz seed corpus"]
+fn write_seed_corpus() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join(SEED_CORPUS);
+
+    for (target, _) in TARGETS {
+        let dir = root.join(target);
+        fs::create_dir_all(&dir).unwrap();
+
+        for (name, seed) in seeds(target) {
+            fs::write(dir.join(name), seed).unwrap();
+        }
+    }
+}